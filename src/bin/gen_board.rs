@@ -1,6 +1,12 @@
-use std::{fs::read_to_string, path::PathBuf};
+use std::{fs::read_to_string, path::PathBuf, process::exit};
 
-use land_battle_chess::{board_utils::Board, game_logic::Piece};
+use land_battle_chess::{
+    board_utils::{
+        random_setup, validate_composition, validate_setup, Board, SetupStrategy, SetupViolation,
+        Variant,
+    },
+    game_logic::Piece,
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -9,19 +15,66 @@ struct Opt {
     #[structopt(long)]
     player2: bool,
 
+    #[structopt(long, required_unless = "strategy")]
+    path: Option<PathBuf>,
+
+    // generate a random layout instead of reading one: uniform or human
+    #[structopt(long)]
+    strategy: Option<SetupStrategy>,
+
+    // only check the layout against the setup rules, exit 1 if it breaks any
     #[structopt(long)]
-    path: PathBuf,
+    validate: bool,
+
+    // piece set checked by --validate: standard or mini
+    #[structopt(long, default_value = "standard")]
+    variant: Variant,
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let data = read_to_string(opt.path).unwrap();
-    let pieces = serde_json::from_str::<Vec<Vec<String>>>(&data).unwrap();
+    let mut violations = vec![];
+    let pieces: Vec<Vec<_>> = match (opt.strategy, opt.path) {
+        (Some(strategy), _) => random_setup(&mut rand::thread_rng(), strategy),
+        (None, Some(path)) => {
+            let data = read_to_string(path).unwrap();
+            let pieces = serde_json::from_str::<Vec<Vec<String>>>(&data).unwrap();
+            pieces
+                .into_iter()
+                .enumerate()
+                .map(|(y, vec)| {
+                    vec.into_iter()
+                        .enumerate()
+                        .map(|(x, name)| {
+                            let name = name.trim().to_string();
+                            let piece = Piece::from(name.clone());
+                            if (piece == Piece::Empty && !name.is_empty())
+                                || piece == Piece::Opponent
+                            {
+                                violations.push(SetupViolation::UnknownPiece { x, y, name });
+                            }
+                            piece
+                        })
+                        .collect::<Vec<Piece>>()
+                })
+                .collect()
+        }
+        (None, None) => unreachable!(),
+    };
 
-    let pieces: Vec<Vec<_>> = pieces
-        .into_iter()
-        .map(|vec| vec.into_iter().map(Piece::from).collect::<Vec<Piece>>())
-        .collect();
+    if opt.validate {
+        violations.extend(validate_setup(&pieces));
+        violations.extend(validate_composition(&pieces, opt.variant));
+        if violations.is_empty() {
+            println!("ok");
+            return;
+        }
+        println!("{} violation(s):", violations.len());
+        for violation in violations {
+            println!("  {:?}", violation);
+        }
+        exit(1);
+    }
 
     let board = Board::gen(pieces, opt.player2);
     println!("{:?}", board);
@@ -1,7 +1,13 @@
 use crate::game_logic::Piece;
+use crate::railroad::{is_railroad, on_one_line};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use strum::EnumString;
 use tabled::{Table, Tabled};
 
-#[derive(Default)]
+// boards are compared and hashed by their packed lines, for snapshots and
+// repetition keys
+#[derive(Default, Clone, PartialEq, Eq, Hash)]
 pub struct Board {
     pub lines: [u64; 5],
 }
@@ -45,11 +51,8 @@ impl Board {
 
         for y in 6..12u64 {
             for x in 0..5u64 {
-                if y == 3 && (x == 1 || x == 3) {
-                    continue;
-                }
-
-                if y == 5 && (x == 1 || x == 3) {
+                // the opponent's camps start out empty like ours
+                if is_camp(x, y) {
                     continue;
                 }
 
@@ -73,11 +76,41 @@ impl Board {
         true
     }
 
+    pub fn remove_piece(&mut self, x: u64, y: u64) -> Piece {
+        let piece = self.get_piece(x, y);
+        let row = y * 4;
+        self.lines[x as usize] &= !(0xf << row);
+        piece
+    }
+
+    pub fn move_piece(&mut self, x: u64, y: u64, target_x: u64, target_y: u64) {
+        let piece = self.remove_piece(x, y);
+        self.remove_piece(target_x, target_y);
+        self.place_piece(target_x, target_y, piece);
+    }
+
     pub fn get_piece(&self, x: u64, y: u64) -> Piece {
         let line = self.lines[x as usize];
         Piece::from_repr(Self::get_piece_from_line(line, y)).unwrap()
     }
 
+    // squares whose piece differs, as (x, y, old, new)
+    pub fn diff(&self, other: &Board) -> Vec<(u64, u64, Piece, Piece)> {
+        let mut changes = vec![];
+        for x in 0..5u64 {
+            if self.lines[x as usize] == other.lines[x as usize] {
+                continue;
+            }
+            for y in 0..12u64 {
+                let (old, new) = (self.get_piece(x, y), other.get_piece(x, y));
+                if old != new {
+                    changes.push((x, y, old, new));
+                }
+            }
+        }
+        changes
+    }
+
     fn get_piece_from_line(x: u64, y: u64) -> u64 {
         let mask = 0xf;
         let row = y * 4;
@@ -85,6 +118,313 @@ impl Board {
     }
 }
 
+// a player's view of the game: their own pieces as they are and every enemy
+// piece as `Piece::Opponent`, the same markers `Board::gen` places
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct FogBoard(Board);
+
+impl FogBoard {
+    // `own` and `opponent` are the two players' boards in shared coordinates,
+    // only real pieces are taken from each
+    pub fn new(own: &Board, opponent: &Board) -> Self {
+        let mut board = Board::default();
+        for x in 0..5u64 {
+            for y in 0..12u64 {
+                let piece = match (own.get_piece(x, y), opponent.get_piece(x, y)) {
+                    (Piece::Empty | Piece::Opponent, Piece::Empty | Piece::Opponent) => continue,
+                    (Piece::Empty | Piece::Opponent, _) => Piece::Opponent,
+                    (piece, _) => piece,
+                };
+                board.place_piece(x, y, piece);
+            }
+        }
+        FogBoard(board)
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for FogBoard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// 己方布阵 6 行 5 列，第 0 行为大本营所在的底线
+pub const HEADQUARTERS: [(usize, usize); 2] = [(1, 0), (3, 0)];
+// 每方四个行营，呈菱形分布在第 2、4 行，中间一格照常布子
+pub const CAMPS: [(usize, usize); 4] = [(1, 2), (3, 2), (1, 4), (3, 4)];
+// 第 5 行紧挨着分界线；地雷只能布在最后两行
+pub const FRONT_ROW: usize = 5;
+pub const LANDMINE_ROWS: [usize; 2] = [0, 1];
+
+// 全盘 5 列 12 行，player1 占 0..6 行，player2 占 6..12 行（按 11 - y 翻转）
+// own-half row of an absolute square, as used by HEADQUARTERS and CAMPS
+fn own_row(y: u64) -> usize {
+    if y < 6 {
+        y as usize
+    } else {
+        11 - y as usize
+    }
+}
+
+pub fn is_headquarters(x: u64, y: u64) -> bool {
+    HEADQUARTERS.contains(&(x as usize, own_row(y)))
+}
+
+pub fn is_camp(x: u64, y: u64) -> bool {
+    CAMPS.contains(&(x as usize, own_row(y)))
+}
+
+// Checks a move by its squares alone, the server never sees what stands in
+// between: one step along a road, diagonally in or out of a camp, or along
+// the railroad, straight unless the piece is an engineer.
+pub fn legal_step(piece: Piece, (x, y): (u64, u64), (tx, ty): (u64, u64)) -> bool {
+    if x >= 5 || y >= 12 || tx >= 5 || ty >= 12 || (x, y) == (tx, ty) {
+        return false;
+    }
+    // a piece that entered a headquarters stays there
+    if is_headquarters(x, y) {
+        return false;
+    }
+
+    let (dx, dy) = (x.abs_diff(tx), y.abs_diff(ty));
+    // mountains cut the front between the halves except on columns 0, 2 and 4
+    let crosses_front = y.min(ty) == 5 && y.max(ty) == 6;
+    if dx + dy == 1 {
+        return !crosses_front || x % 2 == 0;
+    }
+    if dx == 1 && dy == 1 {
+        return is_camp(x, y) || is_camp(tx, ty);
+    }
+    if !is_railroad(x, y) || !is_railroad(tx, ty) {
+        return false;
+    }
+    piece == Piece::Engineer || on_one_line((x, y), (tx, ty))
+}
+
+// 标准版：每方 26 枚棋子，正好摆满行营以外的 26 格
+pub const PIECE_COUNTS: [(Piece, usize); 12] = [
+    (Piece::Flag, 1),
+    (Piece::Bomb, 2),
+    (Piece::Landmine, 3),
+    (Piece::Engineer, 4),
+    (Piece::Lieutenant, 2),
+    (Piece::Captain, 4),
+    (Piece::Major, 2),
+    (Piece::Colonel, 2),
+    (Piece::Brigadier, 2),
+    (Piece::MajorGeneral, 2),
+    (Piece::General, 1),
+    (Piece::FieldMarshal, 1),
+];
+
+// 简化版：每方 12 枚棋子，没有排长、营长和旅长，棋盘不变
+pub const MINI_PIECE_COUNTS: [(Piece, usize); 12] = [
+    (Piece::Flag, 1),
+    (Piece::Landmine, 2),
+    (Piece::Bomb, 1),
+    (Piece::Engineer, 2),
+    (Piece::Lieutenant, 0),
+    (Piece::Captain, 2),
+    (Piece::Major, 0),
+    (Piece::Colonel, 1),
+    (Piece::Brigadier, 0),
+    (Piece::MajorGeneral, 1),
+    (Piece::General, 1),
+    (Piece::FieldMarshal, 1),
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Standard,
+    Mini,
+}
+
+impl Variant {
+    pub fn piece_counts(self) -> &'static [(Piece, usize)] {
+        match self {
+            Variant::Standard => &PIECE_COUNTS,
+            Variant::Mini => &MINI_PIECE_COUNTS,
+        }
+    }
+}
+
+// how many pieces the variant's army can still move after losing `captured`
+pub fn movable_left(variant: Variant, captured: &[Piece]) -> usize {
+    let total: usize = variant
+        .piece_counts()
+        .iter()
+        .filter(|(piece, _)| piece.is_movable())
+        .map(|(_, count)| count)
+        .sum();
+    total.saturating_sub(captured.iter().filter(|piece| piece.is_movable()).count())
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SetupViolation {
+    WrongShape,
+    UnknownPiece {
+        x: usize,
+        y: usize,
+        name: String,
+    },
+    PieceInCamp {
+        x: usize,
+        y: usize,
+    },
+    FlagNotInHeadquarters {
+        x: usize,
+        y: usize,
+    },
+    BombInFrontRow {
+        x: usize,
+        y: usize,
+    },
+    LandmineOutsideBackRows {
+        x: usize,
+        y: usize,
+    },
+    WrongCount {
+        piece: Piece,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+// checks where pieces are placed on the player's own 6x5 half. rows are
+// counted from the player's own back row, as in the board json files, so the
+// same rules hold for player2 before `Board::gen` flips the rows.
+pub fn validate_setup(pieces: &[Vec<Piece>]) -> Vec<SetupViolation> {
+    if pieces.len() != 6 || pieces.iter().any(|row| row.len() != 5) {
+        return vec![SetupViolation::WrongShape];
+    }
+
+    let mut violations = vec![];
+    for (y, row) in pieces.iter().enumerate() {
+        for (x, &piece) in row.iter().enumerate() {
+            if piece == Piece::Empty {
+                continue;
+            }
+            if CAMPS.contains(&(x, y)) {
+                violations.push(SetupViolation::PieceInCamp { x, y });
+            }
+            if piece == Piece::Flag && !HEADQUARTERS.contains(&(x, y)) {
+                violations.push(SetupViolation::FlagNotInHeadquarters { x, y });
+            }
+            if piece == Piece::Bomb && y == FRONT_ROW {
+                violations.push(SetupViolation::BombInFrontRow { x, y });
+            }
+            if piece == Piece::Landmine && !LANDMINE_ROWS.contains(&y) {
+                violations.push(SetupViolation::LandmineOutsideBackRows { x, y });
+            }
+        }
+    }
+    violations
+}
+
+// checks that each piece appears as many times as the variant allows
+pub fn validate_composition(pieces: &[Vec<Piece>], variant: Variant) -> Vec<SetupViolation> {
+    variant
+        .piece_counts()
+        .iter()
+        .filter_map(|&(piece, expected)| {
+            let actual = pieces.iter().flatten().filter(|&&p| p == piece).count();
+            (actual != expected).then_some(SetupViolation::WrongCount {
+                piece,
+                expected,
+                actual,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum SetupStrategy {
+    // every piece goes to a random square the rules allow
+    Uniform,
+    // landmines around the flag, strong pieces towards the front
+    Human,
+}
+
+// generates a legal 6x5 layout in the same orientation as the board json files
+pub fn random_setup<R: Rng>(rng: &mut R, strategy: SetupStrategy) -> Vec<Vec<Piece>> {
+    // picks a free square by weight, a weight of 0 rules the square out
+    fn place<R: Rng>(
+        rng: &mut R,
+        free: &mut Vec<(usize, usize)>,
+        pieces: &mut [Vec<Piece>],
+        piece: Piece,
+        weight: impl Fn(usize, usize) -> u32,
+    ) -> (usize, usize) {
+        let &(x, y) = free
+            .choose_weighted(rng, |&(x, y)| weight(x, y))
+            .expect("no square left");
+        free.retain(|&square| square != (x, y));
+        pieces[y][x] = piece;
+        (x, y)
+    }
+
+    let human = strategy == SetupStrategy::Human;
+    let mut pieces = vec![vec![Piece::Empty; 5]; 6];
+    let mut free: Vec<(usize, usize)> = (0..6)
+        .flat_map(|y| (0..5).map(move |x| (x, y)))
+        .filter(|square| !CAMPS.contains(square))
+        .collect();
+
+    let (flag_x, flag_y) = place(rng, &mut free, &mut pieces, Piece::Flag, |x, y| {
+        HEADQUARTERS.contains(&(x, y)) as u32
+    });
+    for _ in 0..3 {
+        place(rng, &mut free, &mut pieces, Piece::Landmine, |x, y| {
+            if !LANDMINE_ROWS.contains(&y) {
+                0
+            } else if human && x.abs_diff(flag_x) + y.abs_diff(flag_y) == 1 {
+                8
+            } else {
+                1
+            }
+        });
+    }
+    for _ in 0..2 {
+        place(rng, &mut free, &mut pieces, Piece::Bomb, |_, y| {
+            (y != FRONT_ROW) as u32
+        });
+    }
+
+    let mut rest: Vec<Piece> = PIECE_COUNTS
+        .iter()
+        .filter(|(piece, _)| !matches!(piece, Piece::Flag | Piece::Landmine | Piece::Bomb))
+        .flat_map(|&(piece, count)| std::iter::repeat_n(piece, count))
+        .collect();
+    rest.shuffle(rng);
+    if human {
+        // strongest first, each weighted towards the front row
+        rest.sort_by_key(|piece| std::cmp::Reverse(piece.rank()));
+    }
+    for piece in rest {
+        place(rng, &mut free, &mut pieces, piece, |_, y| {
+            if human {
+                y as u32 + 1
+            } else {
+                1
+            }
+        });
+    }
+
+    pieces
+}
+
+// shown for empty squares so they can't be mistaken for missing output
+pub const EMPTY_MARK: &str = "·";
+
 pub fn piece_name(piece: Piece) -> &'static str {
     match piece {
         Piece::Flag => "军棋",
@@ -100,13 +440,45 @@ pub fn piece_name(piece: Piece) -> &'static str {
         Piece::General => "军长",
         Piece::FieldMarshal => "司令",
         Piece::Opponent => "XXX",
-        _ => "",
+        Piece::Empty => EMPTY_MARK,
     }
 }
 
-impl std::fmt::Debug for Board {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        #[derive(Tabled, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Perspective {
+    Player1,
+    // player2 sits at the other end, rows are shown flipped
+    Player2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    Zh,
+    En,
+}
+
+pub fn piece_name_en(piece: Piece) -> &'static str {
+    match piece {
+        Piece::Flag => "Flag",
+        Piece::Bomb => "Bomb",
+        Piece::Landmine => "Landmine",
+        Piece::Engineer => "Engineer",
+        Piece::Lieutenant => "Lieutenant",
+        Piece::Captain => "Captain",
+        Piece::Major => "Major",
+        Piece::Colonel => "Colonel",
+        Piece::Brigadier => "Brigadier",
+        Piece::MajorGeneral => "MajorGeneral",
+        Piece::General => "General",
+        Piece::FieldMarshal => "FieldMarshal",
+        Piece::Opponent => "XXX",
+        Piece::Empty => EMPTY_MARK,
+    }
+}
+
+impl Board {
+    pub fn render(&self, perspective: Perspective, locale: Locale) -> String {
+        #[derive(Tabled)]
         struct BoardRow {
             pub l0: String,
             pub l1: String,
@@ -115,28 +487,338 @@ impl std::fmt::Debug for Board {
             pub l4: String,
         }
 
-        let mut rows: Vec<_> = (0..12).map(|_| BoardRow::default()).collect();
-        for row in 0..12 {
-            let piece = self.get_piece(0, row);
-            rows[row as usize].l0 = piece_name(piece).into();
+        let name = match locale {
+            Locale::Zh => piece_name,
+            Locale::En => piece_name_en,
+        };
+        let rows: Vec<_> = (0..12u64)
+            .map(|row| {
+                let y = match perspective {
+                    Perspective::Player1 => row,
+                    Perspective::Player2 => 11 - row,
+                };
+                BoardRow {
+                    l0: name(self.get_piece(0, y)).into(),
+                    l1: name(self.get_piece(1, y)).into(),
+                    l2: name(self.get_piece(2, y)).into(),
+                    l3: name(self.get_piece(3, y)).into(),
+                    l4: name(self.get_piece(4, y)).into(),
+                }
+            })
+            .collect();
+
+        Table::new(rows).to_string()
+    }
+}
+
+impl std::fmt::Debug for Board {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(Perspective::Player1, Locale::Zh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn load(name: &str) -> Vec<Vec<Piece>> {
+        let path = format!("{}/data/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let names: Vec<Vec<String>> =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        names
+            .into_iter()
+            .map(|row| row.into_iter().map(Piece::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn shipped_layouts_validate() {
+        for name in ["player1.json", "player2.json"] {
+            let pieces = load(name);
+            assert_eq!(validate_setup(&pieces), vec![], "{}", name);
+            assert_eq!(
+                validate_composition(&pieces, Variant::Standard),
+                vec![],
+                "{}",
+                name
+            );
         }
-        for row in 0..12 {
-            let piece = self.get_piece(1, row);
-            rows[row as usize].l1 = piece_name(piece).into();
+    }
+
+    #[test]
+    fn invalid_layout_is_rejected() {
+        let pieces = load("invalid.json");
+        assert!(
+            validate_setup(&pieces).contains(&SetupViolation::FlagNotInHeadquarters { x: 0, y: 0 })
+        );
+        assert!(validate_setup(&pieces).contains(&SetupViolation::PieceInCamp { x: 1, y: 2 }));
+        assert!(!validate_composition(&pieces, Variant::Standard).is_empty());
+    }
+
+    #[test]
+    fn placement_violations() {
+        let mut pieces = load("player1.json");
+        // bomb onto the front row, landmine out of the back rows
+        pieces[5][0] = Piece::Bomb;
+        pieces[3][0] = Piece::Landmine;
+        pieces[4][1] = Piece::Engineer;
+        assert_eq!(
+            validate_setup(&pieces),
+            vec![
+                SetupViolation::LandmineOutsideBackRows { x: 0, y: 3 },
+                SetupViolation::PieceInCamp { x: 1, y: 4 },
+                SetupViolation::BombInFrontRow { x: 0, y: 5 },
+            ]
+        );
+
+        pieces.pop();
+        assert_eq!(validate_setup(&pieces), vec![SetupViolation::WrongShape]);
+    }
+
+    #[test]
+    fn wrong_count() {
+        let mut pieces = load("player1.json");
+        pieces[0][4] = Piece::Captain;
+        assert_eq!(
+            validate_composition(&pieces, Variant::Standard),
+            vec![
+                SetupViolation::WrongCount {
+                    piece: Piece::Engineer,
+                    expected: 4,
+                    actual: 3,
+                },
+                SetupViolation::WrongCount {
+                    piece: Piece::Captain,
+                    expected: 4,
+                    actual: 5,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mini_composition() {
+        use Piece::*;
+
+        let mut pieces = vec![
+            vec![Landmine, Flag, Landmine, Empty, Empty],
+            vec![Bomb, Empty, Empty, Empty, Empty],
+            vec![Engineer, Empty, Empty, Empty, Engineer],
+            vec![Captain, Colonel, Empty, MajorGeneral, Captain],
+            vec![Empty, Empty, General, Empty, Empty],
+            vec![FieldMarshal, Empty, Empty, Empty, Empty],
+        ];
+        assert_eq!(validate_setup(&pieces), vec![]);
+        assert_eq!(validate_composition(&pieces, Variant::Mini), vec![]);
+        assert!(!validate_composition(&pieces, Variant::Standard).is_empty());
+
+        // pieces of the standard army only
+        pieces[5][1] = Lieutenant;
+        assert_eq!(
+            validate_composition(&pieces, Variant::Mini),
+            vec![SetupViolation::WrongCount {
+                piece: Lieutenant,
+                expected: 0,
+                actual: 1,
+            }]
+        );
+        assert!(!validate_composition(&load("player1.json"), Variant::Mini).is_empty());
+    }
+
+    #[test]
+    fn four_camps_per_side() {
+        let camps: Vec<_> = (0..12)
+            .flat_map(|y| (0..5).map(move |x| (x, y)))
+            .filter(|&(x, y)| is_camp(x, y))
+            .collect();
+        assert_eq!(
+            camps,
+            vec![
+                (1, 2),
+                (3, 2),
+                (1, 4),
+                (3, 4),
+                (1, 7),
+                (3, 7),
+                (1, 9),
+                (3, 9)
+            ]
+        );
+        // the centre of the diamond is an ordinary square
+        assert!(!is_camp(2, 3) && !is_camp(2, 8));
+
+        let mut pieces = load("player1.json");
+        pieces[4][3] = Piece::Flag;
+        assert!(validate_setup(&pieces).contains(&SetupViolation::PieceInCamp { x: 3, y: 4 }));
+    }
+
+    #[test]
+    fn gen_leaves_every_camp_empty() {
+        let pieces = load("player1.json");
+        for is_player2 in [false, true] {
+            let board = Board::gen(pieces.clone(), is_player2);
+            for (x, y) in (0..12).flat_map(|y| (0..5).map(move |x| (x, y))) {
+                let own_y = if is_player2 { 11 - y } else { y };
+                let expected = if is_camp(x, y) {
+                    Piece::Empty
+                } else if own_y < 6 {
+                    pieces[own_y as usize][x as usize]
+                } else {
+                    Piece::Opponent
+                };
+                assert_eq!(board.get_piece(x, y), expected, "({}, {})", x, y);
+            }
         }
-        for row in 0..12 {
-            let piece = self.get_piece(2, row);
-            rows[row as usize].l2 = piece_name(piece).into();
+    }
+
+    #[test]
+    fn movable_pieces() {
+        assert_eq!(movable_left(Variant::Standard, &[]), 22);
+        assert_eq!(movable_left(Variant::Mini, &[]), 9);
+        let captured = [Piece::Bomb, Piece::Landmine, Piece::Flag, Piece::Engineer];
+        assert_eq!(movable_left(Variant::Standard, &captured), 20);
+
+        // the shipped layout holds exactly the army movable_left counts from
+        let movable = load("player1.json")
+            .iter()
+            .flatten()
+            .filter(|piece| piece.is_movable())
+            .count();
+        assert_eq!(movable, movable_left(Variant::Standard, &[]));
+    }
+
+    #[test]
+    fn diff_of_a_move_and_a_capture() {
+        let before = Board::gen(load("player1.json"), false);
+
+        // a lieutenant steps back into the empty camp behind it
+        let mut after = before.clone();
+        after.move_piece(1, 5, 1, 4);
+        assert_eq!(
+            before.diff(&after),
+            vec![
+                (1, 4, Piece::Empty, Piece::Lieutenant),
+                (1, 5, Piece::Lieutenant, Piece::Empty),
+            ]
+        );
+
+        // the front row major takes the opposing piece across the front
+        let mut captured = before.clone();
+        captured.move_piece(0, 5, 0, 6);
+        assert_eq!(
+            before.diff(&captured),
+            vec![
+                (0, 5, Piece::Major, Piece::Empty),
+                (0, 6, Piece::Opponent, Piece::Major),
+            ]
+        );
+        assert_eq!(before.diff(&before), vec![]);
+    }
+
+    #[test]
+    fn render_grid() {
+        let board = Board::gen(load("player1.json"), false);
+        for perspective in [Perspective::Player1, Perspective::Player2] {
+            for locale in [Locale::Zh, Locale::En] {
+                let rendered = board.render(perspective, locale);
+                let rows: Vec<_> = rendered
+                    .lines()
+                    .filter(|line| line.starts_with('|'))
+                    .collect();
+                // a header row plus the 12 rows of the board, 5 cells each
+                assert_eq!(rows.len(), 13, "{}", rendered);
+                for row in rows {
+                    assert_eq!(row.matches('|').count(), 6, "{}", row);
+                }
+            }
         }
-        for row in 0..12 {
-            let piece = self.get_piece(3, row);
-            rows[row as usize].l3 = piece_name(piece).into();
+
+        // the first line below the header is row 0 for player1, row 11 for player2
+        let cells = |perspective, locale| {
+            let rendered = board.render(perspective, locale);
+            let row = rendered
+                .lines()
+                .filter(|line| line.starts_with('|'))
+                .nth(1)
+                .unwrap()
+                .to_string();
+            row.split('|')
+                .map(|cell| cell.trim().to_string())
+                .filter(|cell| !cell.is_empty())
+                .collect::<Vec<_>>()
+        };
+        let first = load("player1.json")[0].clone();
+        assert_eq!(
+            cells(Perspective::Player1, Locale::Zh),
+            first.iter().map(|&p| piece_name(p)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            cells(Perspective::Player1, Locale::En),
+            first.iter().map(|&p| piece_name_en(p)).collect::<Vec<_>>()
+        );
+        assert_eq!(cells(Perspective::Player2, Locale::En), vec!["XXX"; 5]);
+    }
+
+    #[test]
+    fn clone_eq_and_hash() {
+        use std::collections::HashSet;
+
+        let board = Board::gen(load("player1.json"), false);
+        let copy = board.clone();
+        assert_eq!(copy, board);
+
+        let mut moved = board.clone();
+        moved.move_piece(1, 5, 1, 4);
+        assert_ne!(moved, board);
+
+        let seen: HashSet<Board> = [board, copy, moved].into_iter().collect();
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn random_setups_validate() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for strategy in [SetupStrategy::Uniform, SetupStrategy::Human] {
+            for _ in 0..50 {
+                let pieces = random_setup(&mut rng, strategy);
+                assert_eq!(validate_setup(&pieces), vec![]);
+                assert_eq!(validate_composition(&pieces, Variant::Standard), vec![]);
+            }
         }
-        for row in 0..12 {
-            let piece = self.get_piece(4, row);
-            rows[row as usize].l4 = piece_name(piece).into();
+    }
+
+    #[test]
+    fn human_setups_mine_around_the_flag() {
+        // landmines on a square next to the flag, over many setups
+        fn next_to_flag(rng: &mut StdRng, strategy: SetupStrategy) -> usize {
+            (0..200)
+                .map(|_| {
+                    let pieces = random_setup(rng, strategy);
+                    let squares = || (0..6).flat_map(|y| (0..5).map(move |x| (x, y)));
+                    let (fx, fy) = squares()
+                        .find(|&(x, y)| pieces[y][x] == Piece::Flag)
+                        .unwrap();
+                    squares()
+                        .filter(|&(x, y)| {
+                            pieces[y][x] == Piece::Landmine && x.abs_diff(fx) + y.abs_diff(fy) == 1
+                        })
+                        .count()
+                })
+                .sum()
         }
 
-        write!(f, "{}", Table::new(rows))
+        let mut rng = StdRng::seed_from_u64(11);
+        let uniform = next_to_flag(&mut rng, SetupStrategy::Uniform);
+        let human = next_to_flag(&mut rng, SetupStrategy::Human);
+        // a flag in a headquarters has 3 neighbours among the 9 squares left in
+        // the back two rows, so 3 landmines by chance give about 1 per setup
+        assert!(uniform < 300, "uniform {}", uniform);
+        assert!(
+            human > uniform * 3 / 2,
+            "human {} uniform {}",
+            human,
+            uniform
+        );
     }
 }
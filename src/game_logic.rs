@@ -1,10 +1,25 @@
+use std::cmp::Ordering;
+
 use serde::{Deserialize, Serialize};
+
+use crate::board_utils::Board;
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use strum::FromRepr;
+use strum::{EnumString, FromRepr, IntoStaticStr};
 
+// serialized as the numeric repr on the wire, see `piece_by_name` for names
 #[derive(
-    Debug, PartialEq, PartialOrd, Eq, Deserialize_repr, Serialize_repr, Copy, Clone, FromRepr,
+    Debug,
+    PartialEq,
+    Eq,
+    Deserialize_repr,
+    Serialize_repr,
+    Copy,
+    Clone,
+    FromRepr,
+    EnumString,
+    IntoStaticStr,
 )]
+#[strum(serialize_all = "snake_case")]
 #[repr(u64)]
 pub enum Piece {
     Empty = 0,
@@ -23,6 +38,59 @@ pub enum Piece {
     Opponent = 13,
 }
 
+impl Piece {
+    // combat rank, engineer is the lowest and field marshal the highest.
+    // flag, bomb, landmine and the placeholders have no rank. the ranks are
+    // spelled out so combat does not depend on the enum's numeric values.
+    pub fn rank(self) -> Option<u8> {
+        match self {
+            Piece::Engineer => Some(1),
+            Piece::Lieutenant => Some(2),
+            Piece::Captain => Some(3),
+            Piece::Major => Some(4),
+            Piece::Colonel => Some(5),
+            Piece::Brigadier => Some(6),
+            Piece::MajorGeneral => Some(7),
+            Piece::General => Some(8),
+            Piece::FieldMarshal => Some(9),
+            _ => None,
+        }
+    }
+
+    // flag and landmine stay where they were placed
+    pub fn is_movable(self) -> bool {
+        self == Piece::Bomb || self.rank().is_some()
+    }
+
+    // the flag position is only sent along with the field marshal, whose
+    // death reveals the flag; any other piece must not carry it
+    pub fn flag_pos_valid(self, flag_x: Option<u32>, flag_y: Option<u32>) -> bool {
+        matches!(
+            (self == Piece::FieldMarshal, flag_x, flag_y),
+            (true, Some(_), Some(_)) | (false, None, None)
+        )
+    }
+}
+
+// `#[serde(with = "piece_by_name")]` (de)serializes a piece as `"general"`
+// instead of `11`, for replays and debug output
+pub mod piece_by_name {
+    use std::str::FromStr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::Piece;
+
+    pub fn serialize<S: Serializer>(piece: &Piece, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str((*piece).into())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Piece, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Piece::from_str(&name).map_err(|_| D::Error::custom(format!("unknown piece {}", name)))
+    }
+}
+
 pub struct PieceInfo {
     pub piece: Piece,
 
@@ -62,6 +130,12 @@ pub struct PieceMove {
     pub opp_flag_y: Option<u32>,
 
     pub game_winner: u32,
+
+    // filled in by the game service, starting from 1
+    #[serde(default)]
+    pub move_number: u32,
+    #[serde(default)]
+    pub timestamp_ms: u64,
 }
 
 pub fn compare_piece(attacker: PieceInfo, target: PieceInfo, move_pos: MovePos) -> PieceMove {
@@ -87,10 +161,16 @@ pub fn compare_piece(attacker: PieceInfo, target: PieceInfo, move_pos: MovePos)
         } else {
             attack_result = AttackResult::Lose;
         }
-    } else if attacker.piece > target.piece {
+    } else if let (Some(attacker_rank), Some(target_rank)) =
+        (attacker.piece.rank(), target.piece.rank())
+    {
+        attack_result = match attacker_rank.cmp(&target_rank) {
+            Ordering::Greater => AttackResult::Win,
+            Ordering::Equal => AttackResult::Draw,
+            Ordering::Less => AttackResult::Lose,
+        };
+    } else if target.piece == Piece::Flag && attacker.piece.is_movable() {
         attack_result = AttackResult::Win;
-    } else if attacker.piece == target.piece {
-        attack_result = AttackResult::Draw;
     } else {
         attack_result = AttackResult::Lose;
     }
@@ -136,5 +216,322 @@ pub fn compare_piece(attacker: PieceInfo, target: PieceInfo, move_pos: MovePos)
         opp_flag_x,
         opp_flag_y,
         game_winner,
+        move_number: 0,
+        timestamp_ms: 0,
+    }
+}
+
+// What both squares of an attack held on each board before it was resolved,
+// as (x, y, piece). Restoring them reverts the attack exactly, including a
+// draw where both pieces are gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttackUndo {
+    pub attacker_board: [(u64, u64, Piece); 2],
+    pub defender_board: [(u64, u64, Piece); 2],
+}
+
+impl AttackUndo {
+    pub fn apply(&self, attacker_board: &mut Board, defender_board: &mut Board) {
+        for (board, squares) in [
+            (attacker_board, &self.attacker_board),
+            (defender_board, &self.defender_board),
+        ] {
+            for &(x, y, piece) in squares {
+                board.remove_piece(x, y);
+                board.place_piece(x, y, piece);
+            }
+        }
+    }
+}
+
+// Adjudicates the attack like `compare_piece` and applies the outcome to both
+// boards. Each board holds its owner's pieces and `Piece::Opponent` markers
+// for the other side, in the shared absolute coordinates built by `Board::gen`.
+pub fn resolve_attack(
+    attacker_board: &mut Board,
+    defender_board: &mut Board,
+    attacker: PieceInfo,
+    target: PieceInfo,
+    move_pos: MovePos,
+) -> (PieceMove, AttackUndo) {
+    let (x, y) = (move_pos.x as u64, move_pos.y as u64);
+    let (target_x, target_y) = (move_pos.target_x as u64, move_pos.target_y as u64);
+    let squares = |board: &Board| {
+        [
+            (x, y, board.get_piece(x, y)),
+            (target_x, target_y, board.get_piece(target_x, target_y)),
+        ]
+    };
+    let undo = AttackUndo {
+        attacker_board: squares(attacker_board),
+        defender_board: squares(defender_board),
+    };
+    let piece_move = compare_piece(attacker, target, move_pos);
+
+    match piece_move.attack_result {
+        AttackResult::SimpleMove | AttackResult::Win => {
+            attacker_board.move_piece(x, y, target_x, target_y);
+            defender_board.move_piece(x, y, target_x, target_y);
+        }
+        AttackResult::Draw => {
+            attacker_board.remove_piece(x, y);
+            attacker_board.remove_piece(target_x, target_y);
+            defender_board.remove_piece(x, y);
+            defender_board.remove_piece(target_x, target_y);
+        }
+        AttackResult::Lose => {
+            attacker_board.remove_piece(x, y);
+            defender_board.remove_piece(x, y);
+        }
+    }
+
+    (piece_move, undo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_and_movability() {
+        let ranked = [
+            Piece::Engineer,
+            Piece::Lieutenant,
+            Piece::Captain,
+            Piece::Major,
+            Piece::Colonel,
+            Piece::Brigadier,
+            Piece::MajorGeneral,
+            Piece::General,
+            Piece::FieldMarshal,
+        ];
+        for (rank, piece) in (1..).zip(ranked) {
+            assert_eq!(piece.rank(), Some(rank), "{:?}", piece);
+            assert!(piece.is_movable(), "{:?}", piece);
+        }
+
+        for piece in [Piece::Empty, Piece::Flag, Piece::Landmine, Piece::Opponent] {
+            assert_eq!(piece.rank(), None, "{:?}", piece);
+            assert!(!piece.is_movable(), "{:?}", piece);
+        }
+        // the bomb moves but has no rank, it takes down whatever it meets
+        assert_eq!(Piece::Bomb.rank(), None);
+        assert!(Piece::Bomb.is_movable());
+    }
+
+    fn info(piece: Piece) -> PieceInfo {
+        let flag = (piece == Piece::FieldMarshal).then_some(0);
+        PieceInfo {
+            piece,
+            flag_x: flag,
+            flag_y: flag,
+        }
+    }
+
+    // attacker at (0, 6) steps onto (0, 5); each board sees the other side's
+    // piece as an opponent marker
+    fn attack(attacker: Piece, target: Piece) -> (PieceMove, Board, Board) {
+        let mut attacker_board = Board::default();
+        let mut defender_board = Board::default();
+        attacker_board.place_piece(0, 6, attacker);
+        defender_board.place_piece(0, 6, Piece::Opponent);
+        if target != Piece::Empty {
+            attacker_board.place_piece(0, 5, Piece::Opponent);
+            defender_board.place_piece(0, 5, target);
+        }
+        let move_pos = MovePos {
+            x: 0,
+            y: 6,
+            target_x: 0,
+            target_y: 5,
+        };
+        let (piece_move, _) = resolve_attack(
+            &mut attacker_board,
+            &mut defender_board,
+            info(attacker),
+            info(target),
+            move_pos,
+        );
+        (piece_move, attacker_board, defender_board)
+    }
+
+    // what the attacker square and the target square hold afterwards on the
+    // attacker's and the defender's board
+    fn squares(board: &Board) -> (Piece, Piece) {
+        (board.get_piece(0, 6), board.get_piece(0, 5))
+    }
+
+    #[test]
+    fn resolve_attack_outcomes() {
+        use Piece::*;
+
+        let cases = [
+            (
+                Captain,
+                Empty,
+                AttackResult::SimpleMove,
+                (Empty, Captain),
+                (Empty, Opponent),
+            ),
+            (
+                General,
+                Colonel,
+                AttackResult::Win,
+                (Empty, General),
+                (Empty, Opponent),
+            ),
+            (
+                Major,
+                Major,
+                AttackResult::Draw,
+                (Empty, Empty),
+                (Empty, Empty),
+            ),
+            (
+                Lieutenant,
+                Brigadier,
+                AttackResult::Lose,
+                (Empty, Opponent),
+                (Empty, Brigadier),
+            ),
+            (
+                Engineer,
+                Landmine,
+                AttackResult::Win,
+                (Empty, Engineer),
+                (Empty, Opponent),
+            ),
+            (
+                FieldMarshal,
+                Landmine,
+                AttackResult::Lose,
+                (Empty, Opponent),
+                (Empty, Landmine),
+            ),
+            (
+                Bomb,
+                FieldMarshal,
+                AttackResult::Draw,
+                (Empty, Empty),
+                (Empty, Empty),
+            ),
+            (
+                Engineer,
+                Bomb,
+                AttackResult::Draw,
+                (Empty, Empty),
+                (Empty, Empty),
+            ),
+            (
+                Lieutenant,
+                Flag,
+                AttackResult::Win,
+                (Empty, Lieutenant),
+                (Empty, Opponent),
+            ),
+        ];
+        for (attacker, target, result, attacker_squares, defender_squares) in cases {
+            let (piece_move, attacker_board, defender_board) = attack(attacker, target);
+            let case = format!("{:?} -> {:?}", attacker, target);
+            assert_eq!(piece_move.attack_result, result, "{}", case);
+            assert_eq!(squares(&attacker_board), attacker_squares, "{}", case);
+            assert_eq!(squares(&defender_board), defender_squares, "{}", case);
+        }
+    }
+
+    #[test]
+    fn flag_capture_and_field_marshal_loss() {
+        let (piece_move, _, _) = attack(Piece::Engineer, Piece::Flag);
+        assert_eq!(piece_move.game_winner, 1);
+
+        // losing the field marshal reveals the flag of its side
+        let (piece_move, _, _) = attack(Piece::FieldMarshal, Piece::Bomb);
+        assert_eq!(piece_move.game_winner, 0);
+        assert_eq!((piece_move.flag_x, piece_move.flag_y), (Some(0), Some(0)));
+        assert_eq!((piece_move.opp_flag_x, piece_move.opp_flag_y), (None, None));
+
+        let (piece_move, _, _) = attack(Piece::General, Piece::FieldMarshal);
+        assert_eq!(piece_move.attack_result, AttackResult::Lose);
+        assert_eq!((piece_move.flag_x, piece_move.flag_y), (None, None));
+        assert_eq!((piece_move.opp_flag_x, piece_move.opp_flag_y), (None, None));
+
+        let (piece_move, _, _) = attack(Piece::Engineer, Piece::Landmine);
+        assert_eq!(piece_move.game_winner, 0);
+    }
+
+    #[test]
+    fn undo_restores_both_boards() {
+        use Piece::*;
+
+        for (attacker, target) in [
+            (Captain, Empty),
+            (General, Colonel),
+            (Major, Major),
+            (Lieutenant, Brigadier),
+        ] {
+            let mut attacker_board = Board::default();
+            let mut defender_board = Board::default();
+            attacker_board.place_piece(2, 6, attacker);
+            defender_board.place_piece(2, 6, Opponent);
+            if target != Empty {
+                attacker_board.place_piece(2, 5, Opponent);
+                defender_board.place_piece(2, 5, target);
+            }
+            let before = (attacker_board.clone(), defender_board.clone());
+
+            let move_pos = MovePos {
+                x: 2,
+                y: 6,
+                target_x: 2,
+                target_y: 5,
+            };
+            let (_, undo) = resolve_attack(
+                &mut attacker_board,
+                &mut defender_board,
+                info(attacker),
+                info(target),
+                move_pos,
+            );
+            assert_ne!((attacker_board.clone(), defender_board.clone()), before);
+
+            undo.apply(&mut attacker_board, &mut defender_board);
+            assert_eq!(
+                (attacker_board, defender_board),
+                before,
+                "{:?} -> {:?}",
+                attacker,
+                target
+            );
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Named {
+        #[serde(with = "piece_by_name")]
+        piece: Piece,
+    }
+
+    #[test]
+    fn piece_numeric_and_named_forms() {
+        for piece in (0..=13).map(|repr| Piece::from_repr(repr).unwrap()) {
+            let numeric = serde_json::to_string(&piece).unwrap();
+            assert_eq!(numeric, (piece as u64).to_string());
+            assert_eq!(serde_json::from_str::<Piece>(&numeric).unwrap(), piece);
+
+            let named = serde_json::to_string(&Named { piece }).unwrap();
+            assert_eq!(
+                serde_json::from_str::<Named>(&named).unwrap(),
+                Named { piece }
+            );
+        }
+
+        let general = serde_json::to_string(&Named {
+            piece: Piece::General,
+        })
+        .unwrap();
+        assert_eq!(general, r#"{"piece":"general"}"#);
+        let marshal: Named = serde_json::from_str(r#"{"piece":"field_marshal"}"#).unwrap();
+        assert_eq!(marshal.piece, Piece::FieldMarshal);
+        assert!(serde_json::from_str::<Named>(r#"{"piece":"king"}"#).is_err());
     }
 }
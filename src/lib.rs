@@ -1,6 +1,11 @@
 pub mod board_utils;
 pub mod game_logic;
 mod log_utils;
+pub mod metrics;
+pub mod railroad;
+pub mod replay;
 pub mod types;
 
-pub use log_utils::setup_log_dispatch;
+pub use game_logic::{AttackResult, MovePos, Piece, PieceMove};
+pub use log_utils::{setup_log_dispatch, short_addr};
+pub use types::GameMessage;
@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use aleo_rust::{Address, Testnet3};
 use fern::{
     colors::{Color, ColoredLevelConfig},
     Output,
@@ -30,3 +31,28 @@ pub fn setup_log_dispatch(log_path: Option<PathBuf>) -> eyre::Result<fern::Dispa
         })
         .chain(log_output))
 }
+
+// renders an address as `aleo17e9…y8cj` for log lines
+pub fn short_addr(addr: &Address<Testnet3>) -> String {
+    let addr = addr.to_string();
+    if addr.len() <= 13 {
+        return addr;
+    }
+    format!("{}…{}", &addr[..8], &addr[addr.len() - 4..])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn short_addr_keeps_prefix_and_suffix() {
+        let addr = Address::<Testnet3>::from_str(
+            "aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj",
+        )
+        .unwrap();
+        assert_eq!(short_addr(&addr), "aleo17e9…y8cj");
+    }
+}
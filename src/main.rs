@@ -1,16 +1,31 @@
 use std::convert::TryInto;
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::pin::Pin;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use aleo_rust::{Address, PrivateKey, Testnet3};
+use aleo_rust::{Address, PrivateKey, Signature, Testnet3};
 use axum::{
-    body::{self},
+    async_trait,
     extract::{
+        rejection::{PathRejection, QueryRejection},
         ws::{Message, WebSocket},
-        Path, Query, State, WebSocketUpgrade,
+        FromRequestParts, Path, Query, State, WebSocketUpgrade,
     },
-    http::{HeaderValue, Method, Response, StatusCode},
-    response::IntoResponse,
-    routing::get,
+    http::{request::Parts, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Json, Router,
 };
 use colored::Colorize;
@@ -18,16 +33,31 @@ use eyre::{bail, eyre, Context};
 use indoc::indoc;
 
 use futures::stream::SplitSink;
-use futures::{sink::SinkExt, stream::StreamExt};
-use land_battle_chess::game_logic::{compare_piece, MovePos, PieceInfo};
-use land_battle_chess::{setup_log_dispatch, types::*};
-use log::{error, info, warn};
+use futures::{
+    sink::{Sink, SinkExt},
+    stream::StreamExt,
+};
+use land_battle_chess::board_utils::{
+    is_camp, legal_step, movable_left, validate_composition, validate_setup, SetupViolation,
+    Variant,
+};
+use land_battle_chess::game_logic::{
+    compare_piece, AttackResult, MovePos, Piece, PieceInfo, PieceMove,
+};
+use land_battle_chess::metrics::Metrics;
+use land_battle_chess::replay::{export_text, load_game, replay_path, save_game};
+use land_battle_chess::{setup_log_dispatch, short_addr, types::*};
+use log::{debug, error, info, warn};
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+use serde::de::DeserializeOwned;
 use structopt::StructOpt;
 
 use tokio::sync::{
     mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-    RwLock,
+    oneshot, RwLock,
 };
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Instant};
 
 use tower_http::cors::CorsLayer;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
@@ -37,6 +67,55 @@ use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 struct Opt {
     #[structopt(long)]
     log_path: Option<PathBuf>,
+
+    // move clock: the player to move has this long to send a Move and the
+    // defender whisper_timeout_secs to answer it, 0 turns either off
+    #[structopt(long, default_value = "60")]
+    turn_timeout_secs: u64,
+
+    #[structopt(long, default_value = "30")]
+    whisper_timeout_secs: u64,
+
+    #[structopt(long, default_value = "600")]
+    lobby_ttl_secs: u64,
+
+    // 0 disables the websocket ping
+    #[structopt(long, default_value = "15")]
+    heartbeat_secs: u64,
+
+    #[structopt(long, default_value = "5")]
+    send_timeout_secs: u64,
+
+    #[structopt(long, default_value = "1000")]
+    max_games: usize,
+
+    #[structopt(long, default_value = "1800")]
+    idle_game_secs: u64,
+
+    #[structopt(long, default_value = "4096")]
+    history_cap: usize,
+
+    #[structopt(long, default_value = "1024")]
+    audit_cap: usize,
+
+    // evicted public messages are appended to <spill_dir>/<game_id>.jsonl
+    #[structopt(long)]
+    spill_dir: Option<PathBuf>,
+
+    // finished games are saved to <replay_dir>/<game_id>.replay.jsonl and
+    // stay available from /replay after the game is gone
+    #[structopt(long)]
+    replay_dir: Option<PathBuf>,
+
+    // every audit entry is appended to <audit_dir>/<game_id>.audit.jsonl, and
+    // the arbiter's signature over them to <audit_dir>/<game_id>.transcript.json
+    // when the game ends
+    #[structopt(long)]
+    audit_dir: Option<PathBuf>,
+
+    // seeds the server's random source, for reproducible test runs
+    #[structopt(long)]
+    rng_seed: Option<u64>,
 }
 
 #[tokio::main]
@@ -55,11 +134,61 @@ async fn main() -> eyre::Result<()> {
         .map_err(|e| eyre!(e))
         .wrap_err("parse arbiter privkey")?;
 
-    let app_state = App::init(arbiter);
+    let timeouts = Timeouts {
+        turn: Duration::from_secs(opt.turn_timeout_secs),
+        whisper: Duration::from_secs(opt.whisper_timeout_secs),
+        lobby_ttl: Duration::from_secs(opt.lobby_ttl_secs),
+        heartbeat: Duration::from_secs(opt.heartbeat_secs),
+        send: Duration::from_secs(opt.send_timeout_secs),
+    };
+    info!("timeouts: {:?}", timeouts);
+
+    // the admin endpoints stay disabled without a token
+    let admin_token = std::env::var("ADMIN_TOKEN").ok();
+
+    info!("max games: {}", opt.max_games);
+
+    let limits = Limits {
+        history_cap: opt.history_cap,
+        audit_cap: opt.audit_cap,
+        spill_dir: opt.spill_dir,
+        replay_dir: opt.replay_dir,
+        audit_dir: opt.audit_dir,
+    };
+    info!("limits: {:?}", limits);
+
+    let (events_tx, events_rx) = unbounded_channel();
+    let app_state = App::init(
+        arbiter,
+        timeouts,
+        limits,
+        admin_token,
+        opt.max_games,
+        opt.rng_seed,
+        events_tx,
+    );
+    tokio::spawn(handle_game_events(app_state.clone(), events_rx));
+    tokio::spawn(reap_idle_games(
+        app_state.clone(),
+        Duration::from_secs(opt.idle_game_secs),
+    ));
     let app = Router::new()
         .route("/join", get(join))
         .route("/join/:pubkey", get(join_get))
+        .route("/lobby/create", get(lobby_create))
+        .route("/matchmake", get(matchmake))
+        .route("/matchmake/cancel", get(matchmake_cancel))
         .route("/game", get(enter_game))
+        .route("/game/:game_id/turn", get(turn))
+        .route("/spectate", get(spectate))
+        .route("/replay/:game_id", get(replay))
+        .route("/validate-board", post(validate_board))
+        .route("/adjudicate", post(adjudicate))
+        .route("/rules/combat", get(combat_rules))
+        .route("/metrics", get(metrics))
+        .route("/admin/game/:game_id/audit", get(audit))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
         .layer(
             CorsLayer::new()
                 .allow_origin("http://localhost:8080".parse::<HeaderValue>().unwrap())
@@ -69,34 +198,341 @@ async fn main() -> eyre::Result<()> {
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         )
-        .with_state(app_state);
+        .layer(middleware::map_request(hide_ticket_headers))
+        .with_state(app_state.clone());
 
+    let ready = app_state.read().await.ready.clone();
     let addr = SocketAddr::from_str("127.0.0.1:3000").unwrap();
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+    ready.store(true, Ordering::Relaxed);
+    server
+        .with_graceful_shutdown(shutdown_signal(ready))
         .await
         .unwrap();
     Ok(())
 }
 
+// on ctrl-c readiness goes down first, giving load balancers SHUTDOWN_DRAIN
+// to stop sending traffic before the server stops accepting connections
+async fn shutdown_signal(ready: Arc<AtomicBool>) {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("listen for ctrl-c, error: {:?}", e);
+        return;
+    }
+    info!("shutting down, draining for {:?}", SHUTDOWN_DRAIN);
+    ready.store(false, Ordering::Relaxed);
+    tokio::time::sleep(SHUTDOWN_DRAIN).await;
+}
+
 type GameId = u64;
 
+// what join answers while the player still waits for an opponent, never
+// handed out as a real game id
+const PENDING_GAME_ID: GameId = 0;
+
+// mints ids for new games, drawing from App::rng unless replaced, e.g. by a
+// plain sequence in tests
+type GameIdGen = Box<dyn FnMut(&mut StdRng) -> GameId + Send + Sync>;
+
 struct App {
     user_map: HashMap<Address<Testnet3>, User>,
     game_map: HashMap<GameId, Game>,
     arbiter: (PrivateKey<Testnet3>, Address<Testnet3>),
+    metrics: Arc<Metrics>,
+    timeouts: Timeouts,
+    // every random draw (game ids, lobby codes) comes from here, so a fixed
+    // --rng-seed replays the same values. signature nonces never do, see
+    // `sign_transcript`
+    rng: StdRng,
+    next_game_id: GameIdGen,
+    admin_token: Option<String>,
+    max_games: usize,
+    finished_games: VecDeque<(GameId, (Address<Testnet3>, Address<Testnet3>))>,
+    // players waiting in /matchmake, paired first come first served. they
+    // only enter user_map once paired
+    match_queue: VecDeque<User>,
+    // game services report finished games and ask for rematches here
+    events_tx: UnboundedSender<GameEvent>,
+    limits: Limits,
+    // up once the server accepts connections, down again while shutting down
+    ready: Arc<AtomicBool>,
+    // tickets already used to enter a game with their expiry, each admits
+    // one connection. behind its own lock so entering takes only a read lock
+    used_tickets: Mutex<HashMap<String, u64>>,
+    // players who went back to the match queue from a finished game, with
+    // that game's sender and activity: it hands their socket to the game the
+    // queue finds, the socket keeps bumping the old game's activity
+    requeued: HashMap<Address<Testnet3>, (GameServiceSender, Arc<Mutex<Instant>>)>,
 }
 
 impl App {
-    fn init(arbiter: PrivateKey<Testnet3>) -> Arc<RwLock<App>> {
+    fn init(
+        arbiter: PrivateKey<Testnet3>,
+        timeouts: Timeouts,
+        limits: Limits,
+        admin_token: Option<String>,
+        max_games: usize,
+        rng_seed: Option<u64>,
+        events_tx: UnboundedSender<GameEvent>,
+    ) -> Arc<RwLock<App>> {
         let pubkey = Address::try_from(arbiter).unwrap();
         let app = App {
             arbiter: (arbiter, pubkey),
             user_map: HashMap::new(),
             game_map: HashMap::new(),
+            metrics: Arc::new(Metrics::default()),
+            timeouts,
+            rng: match rng_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            next_game_id: Box::new(|rng| rng.gen()),
+            admin_token,
+            max_games,
+            finished_games: VecDeque::new(),
+            match_queue: VecDeque::new(),
+            events_tx,
+            limits,
+            ready: Arc::new(AtomicBool::new(false)),
+            used_tickets: Mutex::new(HashMap::new()),
+            requeued: HashMap::new(),
         };
         Arc::new(RwLock::new(app))
     }
+
+    // marks a game as decided and frees its players, handing back who they
+    // were. the game is kept until its service closes
+    fn finish_game(&mut self, game_id: GameId) -> Option<(Vec<User>, &Game)> {
+        let game = self.game_map.get_mut(&game_id)?;
+        game.finished = true;
+        let mut users = Vec::new();
+        self.user_map.retain(|_, u| {
+            let playing = u.game_id == Some(game_id);
+            if playing {
+                users.push(u.clone());
+            }
+            !playing
+        });
+        Some((users, &self.game_map[&game_id]))
+    }
+
+    // forgets a stopped game, remembering its players so late comers can be
+    // told it has finished
+    fn remove_game(&mut self, game_id: GameId) -> Option<Game> {
+        let game = self.game_map.remove(&game_id)?;
+        self.user_map.retain(|_, u| u.game_id != Some(game_id));
+        if self.finished_games.len() == FINISHED_GAMES_CAPACITY {
+            self.finished_games.pop_front();
+        }
+        self.finished_games.push_back((game_id, game.players));
+        Some(game)
+    }
+
+    // spawns the game service for two waiting users and records them as
+    // playing it. the first one's random_turn decides whether a coin flip
+    // over both Ready commitments picks the first mover, and its variant is
+    // the army both play with
+    fn start_game(
+        &mut self,
+        first: &User,
+        second: &User,
+        state: &AppState,
+    ) -> Result<GameId, JoinError> {
+        let running = self.game_map.values().filter(|g| !g.finished).count();
+        if running >= self.max_games {
+            warn!("{} games running, refuse new game", running);
+            return Err(JoinError::TooManyGames);
+        }
+        let game_id = self.new_game_id();
+        let (tx, rx) = unbounded_channel();
+        let mut game = Game {
+            players: (first.pubkey, second.pubkey),
+            tx,
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            finished: false,
+            handed_activity: Vec::new(),
+        };
+        let game_svc = GameService::new(
+            game_id,
+            self.arbiter,
+            first.pubkey,
+            second.pubkey,
+            (first.name.clone(), second.name.clone()),
+            first.random_turn,
+            first.variant,
+            self.metrics.clone(),
+            self.timeouts,
+            self.limits.clone(),
+            self.events_tx.clone(),
+        );
+        let handle = tokio::spawn(game_svc.run(rx));
+        tokio::spawn(watch_game_service(game_id, handle, state.clone()));
+        // players queued from a finished game's socket move over to this one
+        for user in [first, second] {
+            if let Some((old_tx, activity)) = self.requeued.remove(&user.pubkey) {
+                let msg = GameServiceMsg::Matched(user.pubkey, Some(game.tx.clone()));
+                _ = old_tx.send(msg);
+                game.handed_activity.push(activity);
+            }
+        }
+        self.game_map.insert(game_id, game);
+        for user in [first, second] {
+            let user = User {
+                game_id: Some(game_id),
+                variant: first.variant,
+                ..user.clone()
+            };
+            self.user_map.insert(user.pubkey, user);
+        }
+        Ok(game_id)
+    }
+
+    // a fresh id, clear of the pending marker and of games still remembered
+    fn new_game_id(&mut self) -> GameId {
+        loop {
+            let game_id = (self.next_game_id)(&mut self.rng);
+            if game_id != PENDING_GAME_ID
+                && !self.game_map.contains_key(&game_id)
+                && !self.finished_games.iter().any(|(id, _)| *id == game_id)
+            {
+                break game_id;
+            }
+        }
+    }
+
+    fn new_token(&mut self) -> String {
+        (&mut self.rng)
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect()
+    }
+
+    // a ticket is "<expiry>.<signature>", the arbiter signing
+    // "<game_id>:<pubkey>:<expiry>" with expiry in unix seconds
+    fn issue_ticket(&self, game_id: GameId, pubkey: Address<Testnet3>) -> Option<String> {
+        let expiry = chrono::Utc::now().timestamp() as u64 + TICKET_TTL.as_secs();
+        let message = format!("{}:{}:{}", game_id, pubkey, expiry);
+        match self
+            .arbiter
+            .0
+            .sign_bytes(message.as_bytes(), &mut rand::thread_rng())
+        {
+            Ok(signature) => Some(format!("{}.{}", expiry, signature)),
+            Err(e) => {
+                error!("[{}] sign ticket, error: {:?}", game_id, e);
+                None
+            }
+        }
+    }
+
+    // besides the ticket itself, the player has to sign it with their own
+    // key: anyone can read a pubkey, only its owner can sign for it. a ticket
+    // that checks out is used up, reconnecting takes a new one from /join/:pubkey
+    fn use_ticket(
+        &self,
+        game_id: GameId,
+        pubkey: Address<Testnet3>,
+        ticket: &str,
+        player_signature: &str,
+    ) -> Result<(), EnterGameError> {
+        let (expiry, signature) = ticket
+            .split_once('.')
+            .ok_or(EnterGameError::InvalidTicket)?;
+        let signature = Signature::<Testnet3>::from_str(signature)
+            .map_err(|_| EnterGameError::InvalidTicket)?;
+        let message = format!("{}:{}:{}", game_id, pubkey, expiry);
+        if !signature.verify_bytes(&self.arbiter.1, message.as_bytes()) {
+            return Err(EnterGameError::InvalidTicket);
+        }
+        let expiry: u64 = expiry.parse().map_err(|_| EnterGameError::InvalidTicket)?;
+        if expiry < chrono::Utc::now().timestamp() as u64 {
+            return Err(EnterGameError::TicketExpired);
+        }
+        let player_signature = Signature::<Testnet3>::from_str(player_signature)
+            .map_err(|_| EnterGameError::InvalidSignature)?;
+        if !player_signature.verify_bytes(&pubkey, ticket.as_bytes()) {
+            return Err(EnterGameError::InvalidSignature);
+        }
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut used_tickets = self.used_tickets.lock().unwrap();
+        used_tickets.retain(|_, expiry| *expiry >= now);
+        if used_tickets.insert(ticket.into(), expiry).is_some() {
+            return Err(EnterGameError::TicketUsed);
+        }
+        Ok(())
+    }
+
+    // the game `player` may enter, finished ones included while they wait for
+    // a rematch, or why not: never heard of, someone else's, or closed
+    fn game_to_enter(
+        &self,
+        game_id: GameId,
+        player: Address<Testnet3>,
+    ) -> Result<&Game, (StatusCode, EnterGameError)> {
+        let players = self
+            .game_map
+            .get(&game_id)
+            .map(|game| game.players)
+            .or_else(|| {
+                self.finished_games
+                    .iter()
+                    .find(|(id, _)| *id == game_id)
+                    .map(|(_, players)| *players)
+            });
+        let Some(players) = players else {
+            return Err((StatusCode::NOT_FOUND, EnterGameError::NotFound));
+        };
+        if players.0 != player && players.1 != player {
+            return Err((StatusCode::BAD_REQUEST, EnterGameError::NotParticipant));
+        }
+        self.game_map
+            .get(&game_id)
+            .ok_or((StatusCode::GONE, EnterGameError::Finished))
+    }
+
+    fn purge_expired_lobbies(&mut self) {
+        let lobby_ttl = self.timeouts.lobby_ttl;
+        self.user_map
+            .retain(|_, u| u.game_id.is_some() || u.joined_at.elapsed() < lobby_ttl);
+        self.match_queue
+            .retain(|u| u.joined_at.elapsed() < lobby_ttl);
+        self.drop_requeued();
+    }
+
+    fn in_match_queue(&self, pubkey: &Address<Testnet3>) -> bool {
+        self.match_queue.iter().any(|u| u.pubkey == *pubkey)
+    }
+
+    // queues `user` for a match, or pairs them at once with the longest
+    // waiting player of the same variant. the new game's id once paired
+    fn enqueue(&mut self, user: User, state: &AppState) -> Result<Option<GameId>, JoinError> {
+        let waiting = self
+            .match_queue
+            .iter()
+            .position(|u| u.variant == user.variant);
+        let Some(i) = waiting else {
+            self.match_queue.push_back(user);
+            return Ok(None);
+        };
+        let waiting = self.match_queue[i].clone();
+        let game_id = self.start_game(&waiting, &user, state)?;
+        self.match_queue.remove(i);
+        Ok(Some(game_id))
+    }
+
+    // requeued players who left the queue without a game get their socket
+    // back from the finished game
+    fn drop_requeued(&mut self) {
+        let match_queue = &self.match_queue;
+        self.requeued.retain(|pubkey, (old_tx, _)| {
+            let queued = match_queue.iter().any(|u| u.pubkey == *pubkey);
+            if !queued {
+                _ = old_tx.send(GameServiceMsg::Matched(*pubkey, None));
+            }
+            queued
+        });
+    }
 }
 
 type AppState = Arc<RwLock<App>>;
@@ -106,6 +542,30 @@ struct User {
     pubkey: Address<Testnet3>,
     access_code: String,
     game_id: Option<GameId>,
+    random_turn: bool,
+    variant: Variant,
+    name: String,
+    // handed out once on joining, proves the pubkey when fetching a ticket
+    token: String,
+    // waiting users are dropped after the lobby ttl
+    joined_at: Instant,
+}
+
+const NAME_MAX_CHARS: usize = 24;
+
+// strips control characters and caps the length, falling back to the short
+// address when nothing is left
+fn display_name(name: Option<String>, pubkey: &Address<Testnet3>) -> String {
+    let name: String = name
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(NAME_MAX_CHARS)
+        .collect();
+    match name.trim() {
+        "" => short_addr(pubkey),
+        name => name.to_string(),
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -117,452 +577,3845 @@ enum PlayerState {
 
 struct Player {
     pubkey: Address<Testnet3>,
+    name: String,
     state: PlayerState,
     piece: Option<PieceInfo>,
     move_pos: Option<MovePos>,
+    conn: Option<PlayerConn>,
+    // own pieces that died in combat, public once lost
+    captured: Vec<Piece>,
+    // consecutive failed or timed out sends, reset on success
+    send_failures: u32,
+    typing_sent_at: Option<Instant>,
+    // sent with Ready, goes into the coin flip
+    commitment: String,
 }
 
-#[derive(Debug)]
+// the sending half of a player's socket, boxed so the game service can be
+// driven without a real websocket
+type WsSink = Pin<Box<dyn Sink<Message, Error = axum::Error> + Send>>;
+
 struct PlayerConn {
+    id: ConnId,
     pubkey: Address<Testnet3>,
-    ws_tx: SplitSink<WebSocket, Message>,
+    ws_tx: WsSink,
     exit_signal: Sender<()>,
 }
 
+impl std::fmt::Debug for PlayerConn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PlayerConn")
+            .field("id", &self.id)
+            .field("pubkey", &self.pubkey)
+            .finish_non_exhaustive()
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum GameServiceMsg {
     PlayerConnected(PlayerConn),
+    PlayerDisconnected(Address<Testnet3>, ConnId),
+    SpectatorConnected(SpectatorId, SplitSink<WebSocket, Message>),
+    SpectatorDisconnected(SpectatorId),
+    BinaryFrame(Address<Testnet3>),
+    // the socket task asks for a websocket ping on its connection
+    Heartbeat(Address<Testnet3>, ConnId),
+    Shutdown(String),
+    Replay(oneshot::Sender<Vec<PieceMove>>),
+    Turn(oneshot::Sender<TurnResult>),
+    Audit(oneshot::Sender<Vec<AuditEntry>>),
+    // the match queue found a requeued player a game, or gave up on them
+    Matched(Address<Testnet3>, Option<GameServiceSender>),
     GameMessage(Address<Testnet3>, GameMessage),
 }
 
 type GameServiceSender = UnboundedSender<GameServiceMsg>;
 
+// what a game service needs the app to do, see `handle_game_events`
+#[derive(Debug)]
+enum GameEvent {
+    // the game is decided, its players are free to join another one
+    Finished(GameId),
+    Rematch(Rematch),
+    // a player of the finished game wants to be matched with someone else
+    Requeue(GameId, Address<Testnet3>),
+}
+
+// both players of a finished game asked to play again
+#[derive(Debug)]
+struct Rematch {
+    game_id: GameId,
+    // the new game's first mover, who moved second last time
+    first: Address<Testnet3>,
+    second: Address<Testnet3>,
+    reply: oneshot::Sender<(GameId, GameServiceSender)>,
+}
+type SpectatorId = u64;
+type ConnId = u64;
+
+// tells a player's sockets apart when a new login replaces an old one
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(0);
+// counted rather than drawn from App::rng, so /spectate takes only a read lock
+static NEXT_SPECTATOR_ID: AtomicU64 = AtomicU64::new(0);
+
+const MAX_SEND_FAILURES: u32 = 3;
+const SPECTATORS_THROTTLE: Duration = Duration::from_secs(1);
+const TYPING_THROTTLE: Duration = Duration::from_secs(1);
+const CHAT_MAX_CHARS: usize = 500;
+// commitments are echoed to both in GameStart
+const COMMITMENT_MAX_LEN: usize = 128;
+const LOBBY_CODE_LEN: usize = 6;
+const FINISHED_GAMES_CAPACITY: usize = 1024;
+const REAPER_INTERVAL: Duration = Duration::from_secs(60);
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(5);
+const TICKET_TTL: Duration = Duration::from_secs(300);
+const TOKEN_LEN: usize = 32;
+const REMATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
 struct GameService {
     game_id: GameId,
     arbiter: Address<Testnet3>,
+    // signs the transcript when the game ends
+    arbiter_key: PrivateKey<Testnet3>,
     players: (Player, Player),
     cur_player: Address<Testnet3>,
+    // the army both sides play with, decides when a side has run out of moves
+    variant: Variant,
+    spectators: Vec<(SpectatorId, SplitSink<WebSocket, Message>)>,
+    // viewer count updates are sent at most once per SPECTATORS_THROTTLE
+    spectators_sent_at: Option<Instant>,
+    spectators_flush_at: Option<Instant>,
+    // whether the first mover is flipped for once both are ready
+    random_turn: bool,
+    coin_flip: Option<CoinFlip>,
+    // public messages (GameStart/PiecePos/MoveResult/CapturedPieces/GameOver), seq = index + 1
+    history: VecDeque<GameMessage>,
+    // seq of the last message evicted from history
+    history_base: u64,
+    // adjudicated moves so far
+    moves: u64,
+    started_at: Option<Instant>,
+    // who has to act next and until when, None when the clock is off
+    clock: Option<(Address<Testnet3>, Instant)>,
+    // the player whose draw offer is waiting for an answer
+    draw_offer: Option<Address<Testnet3>>,
+    // who asked for a rematch and when, void after REMATCH_TIMEOUT
+    rematch_request: Option<(Address<Testnet3>, Instant)>,
+    events_tx: UnboundedSender<GameEvent>,
+    metrics: Arc<Metrics>,
+    timeouts: Timeouts,
+    // set once GameOver is sent, gameplay messages are refused afterwards
+    game_over: bool,
+    // a finished game stays up until then, for its players to ask for a rematch
+    close_at: Option<Instant>,
+    // when both players were last seen disconnected
+    abandoned_at: Option<Instant>,
+    limits: Limits,
+    // every message in and out of the game, oldest dropped past limits.audit_cap
+    audit_log: VecDeque<AuditEntry>,
+    audit_seq: u64,
+    // FNV-1a over every audit entry so far, evicted ones included
+    transcript_hash: u64,
+    // players waiting in the match queue on this game's sockets
+    requeued: Vec<Address<Testnet3>>,
+    // sockets handed to another game, by rematch or by the match queue
+    handed_off: Vec<(Address<Testnet3>, ConnId, GameServiceSender)>,
 }
 
 #[derive(Debug)]
 struct Game {
     players: (Address<Testnet3>, Address<Testnet3>),
     tx: GameServiceSender,
+    // bumped by the player sockets on every game message, heartbeats don't count
+    last_activity: Arc<Mutex<Instant>>,
+    // decided, the service stays up for a rematch until it closes. it no
+    // longer counts against max_games and its players are free
+    finished: bool,
+    // sockets requeued from finished games keep bumping those games' activity
+    handed_activity: Vec<Arc<Mutex<Instant>>>,
+}
+
+impl Game {
+    // since the last game message from any of its sockets
+    fn idle_for(&self) -> Duration {
+        self.handed_activity
+            .iter()
+            .chain([&self.last_activity])
+            .map(|activity| activity.lock().unwrap().elapsed())
+            .min()
+            .unwrap()
+    }
 }
 
 impl GameService {
-    async fn run(mut self, mut rx: UnboundedReceiver<GameServiceMsg>, _app_state: AppState) {
+    async fn run(mut self, mut rx: UnboundedReceiver<GameServiceMsg>) {
         let (game_id, player1, player2, arbiter) = (
             self.game_id,
             self.players.0.pubkey,
             self.players.1.pubkey,
             self.arbiter,
         );
-        let mut conns = (None, None);
-        while let Some(data) = rx.recv().await {
+        let (player1_name, player2_name) =
+            (self.players.0.name.clone(), self.players.1.name.clone());
+        let send_timeout = self.timeouts.send;
+        info!(
+            "[{}] game service started, timeouts: {:?}",
+            game_id, self.timeouts
+        );
+        while let Some(data) = self.recv(&mut rx).await {
+            let Some(data) = self.pass_on(data) else {
+                continue;
+            };
+            // whatever a player sends goes on record, even if it is refused
+            if let GameServiceMsg::GameMessage(pubkey, msg) = &data {
+                self.audit(Some(*pubkey), None, msg);
+            }
             match data {
-                GameServiceMsg::PlayerConnected(mut conn) => match self.player_mut(conn.pubkey) {
-                    Some(player) => {
-                        if player.state != PlayerState::Disconnected {
-                            todo!()
-                        } else {
-                            if let Err(e) = conn
-                                .ws_tx
-                                .send(
-                                    GameMessage::Role {
-                                        game_id,
-                                        arbiter,
-                                        player1,
-                                        player2,
-                                    }
-                                    .try_into()
-                                    .unwrap(),
-                                )
-                                .await
-                            {
-                                warn!("[{}] send role to {}, error: {:?}", game_id, conn.pubkey, e);
-                                continue;
-                            }
-                            player.state = PlayerState::Connected;
-                            if conn.pubkey == player1 {
-                                conns.0 = Some(conn)
-                            } else {
-                                conns.1 = Some(conn)
-                            };
-                        }
+                GameServiceMsg::PlayerConnected(mut conn) => {
+                    let pubkey = conn.pubkey;
+                    let started = self.started_at.is_some();
+                    let Some(player) = self.player_mut(pubkey) else {
+                        _ = conn.exit_signal.send(()).await;
+                        continue;
+                    };
+                    let role = GameMessage::Role {
+                        game_id,
+                        arbiter,
+                        player1,
+                        player2,
+                        player1_name: player1_name.clone(),
+                        player2_name: player2_name.clone(),
+                        variant: self.variant,
+                    };
+                    let sent =
+                        timeout(send_timeout, conn.ws_tx.send(role.try_into().unwrap())).await;
+                    if !matches!(sent, Ok(Ok(()))) {
+                        warn!(
+                            "[{}] send role to {}, error: {:?}",
+                            game_id,
+                            short_addr(&pubkey),
+                            sent
+                        );
+                        // the socket task would otherwise wait for its own send to fail
+                        _ = conn.exit_signal.send(()).await;
+                        continue;
                     }
-                    None => {
-                        conn.exit_signal.send(()).await.unwrap();
+
+                    // a second login takes the seat over, the old socket is told and closed
+                    if let Some(mut old) = player.conn.replace(conn) {
+                        info!("[{}] {} logged in again", game_id, short_addr(&pubkey));
+                        let msg = GameMessage::RepeatedLogin { game_id };
+                        _ = timeout(send_timeout, old.ws_tx.send(msg.try_into().unwrap())).await;
+                        _ = old.exit_signal.send(()).await;
                     }
-                },
+                    player.send_failures = 0;
+                    player.state = if started {
+                        PlayerState::Ready
+                    } else {
+                        PlayerState::Connected
+                    };
+                    if started {
+                        self.resume(pubkey).await;
+                    }
+                }
 
-                GameServiceMsg::GameMessage(pubkey, msg) => {
-                    if let (Some(player1), Some(player2)) = (&mut conns.0, &mut conns.1) {
-                        let (tx, opp_tx) = if pubkey == player1.pubkey {
-                            (&mut player1.ws_tx, &mut player2.ws_tx)
-                        } else {
-                            (&mut player2.ws_tx, &mut player1.ws_tx)
-                        };
-                        if let Err(e) = self.process_player_message(msg, pubkey, tx, opp_tx).await {
-                            error!("process player:{} message, error:{:?}", pubkey, e);
+                GameServiceMsg::PlayerDisconnected(pubkey, conn_id) => {
+                    let Some(player) = self.player_mut(pubkey) else {
+                        continue;
+                    };
+                    match player.conn.as_ref().map(|conn| conn.id) {
+                        // the seat was already taken over by a newer login
+                        Some(id) if id != conn_id => continue,
+                        Some(_) => player.conn = None,
+                        // a slow connection may already have been dropped by `send`
+                        None if player.state == PlayerState::Disconnected => continue,
+                        None => {}
+                    }
+                    player.state = PlayerState::Disconnected;
+                    // a pending move is kept on purpose: the opponent has already seen its
+                    // PiecePos and its whisper, which is processed with this seat empty,
+                    // settles the move. the result reaches this player through
+                    // RequestResync after reconnecting
+                    if let Some(opp_pubkey) = self.opponent(pubkey).map(|opp| opp.pubkey) {
+                        _ = self
+                            .send(opp_pubkey, GameMessage::OpponentDisconnected { game_id })
+                            .await;
+                    }
+                }
+
+                GameServiceMsg::SpectatorConnected(id, mut ws_tx) => {
+                    // late joiners catch up on the public history before live updates
+                    let mut result = Ok(Ok(()));
+                    for seq in self.history_base + 1..=self.last_seq() {
+                        let msg = self.sequenced(seq).try_into().unwrap();
+                        result = timeout(send_timeout, ws_tx.send(msg)).await;
+                        if !matches!(result, Ok(Ok(()))) {
+                            break;
+                        }
+                    }
+                    match result {
+                        Ok(Ok(())) => {
+                            self.spectators.push((id, ws_tx));
+                            self.spectators_changed().await;
                         }
+                        e => warn!("[{}] send history to spectator, error: {:?}", game_id, e),
                     }
                 }
-            }
-        }
-    }
 
-    async fn process_player_message(
-        &mut self,
-        msg: GameMessage,
-        pubkey: Address<Testnet3>,
-        player_tx: &mut SplitSink<WebSocket, Message>,
-        opp_tx: &mut SplitSink<WebSocket, Message>,
-    ) -> eyre::Result<()> {
-        let game_id = self.game_id;
-        match msg {
-            GameMessage::Ready { .. } => {
-                let player = self.player_mut(pubkey).unwrap();
-                player.state = PlayerState::Ready;
+                GameServiceMsg::SpectatorDisconnected(id) => {
+                    let count = self.spectators.len();
+                    self.spectators.retain(|(spectator, _)| *spectator != id);
+                    if self.spectators.len() != count {
+                        self.spectators_changed().await;
+                    }
+                }
 
-                if let Some(opp) = self.opponent(pubkey) {
-                    if opp.state == PlayerState::Ready {
-                        let msg: Message = GameMessage::GameStart {
+                GameServiceMsg::Shutdown(reason) => {
+                    info!("[{}] shutting down, {}", game_id, reason);
+                    for pubkey in [player1, player2] {
+                        let msg = GameMessage::Error {
                             game_id,
-                            turn: self.cur_player,
-                        }
-                        .try_into()
-                        .unwrap();
-                        _ = player_tx.send(msg.clone()).await;
-                        _ = opp_tx.send(msg).await;
+                            message: reason.clone(),
+                        };
+                        _ = self.send(pubkey, msg).await;
                     }
+                    break;
                 }
-            }
-            GameMessage::Move {
-                piece,
-                x,
-                y,
-                target_x,
-                target_y,
-                flag_x,
-                flag_y,
-            } => {
-                if self.cur_player != pubkey {
-                    warn!("[{}] not {} turn", game_id, pubkey);
-                    return Ok(());
-                };
 
-                let player = self.player_mut(pubkey).unwrap();
-                if player.piece.is_some() {
-                    warn!("[{}] player:{} has piece", game_id, player.pubkey);
-                    return Ok(());
+                GameServiceMsg::Heartbeat(pubkey, conn_id) => {
+                    let Some(conn) = self.player_mut(pubkey).and_then(|p| p.conn.as_mut()) else {
+                        continue;
+                    };
+                    if conn.id == conn_id {
+                        _ = timeout(send_timeout, conn.ws_tx.send(Message::Ping(vec![]))).await;
+                    }
                 }
 
-                player.piece = Some(PieceInfo {
-                    piece,
-                    flag_x,
-                    flag_y,
-                });
-                let move_pos = MovePos {
-                    x,
-                    y,
-                    target_x,
-                    target_y,
-                };
-                player.move_pos = Some(move_pos.clone());
+                GameServiceMsg::BinaryFrame(pubkey) => {
+                    let msg = GameMessage::Error {
+                        game_id,
+                        message: "binary frames are not supported, send json text".into(),
+                    };
+                    _ = self.send(pubkey, msg).await;
+                }
 
-                let msg: Message = GameMessage::PiecePos(move_pos).try_into().unwrap();
-                opp_tx.send(msg).await.wrap_err("send opp")?;
-            }
-            GameMessage::Whisper {
-                piece,
-                flag_x,
-                flag_y,
-                ..
-            } => {
-                if self.cur_player == pubkey {
-                    warn!("[{}] unexpect whisper from {}", game_id, pubkey);
-                    return Ok(());
-                };
+                GameServiceMsg::Replay(reply_tx) => {
+                    _ = reply_tx.send(self.replay_moves());
+                }
 
-                let target = PieceInfo {
-                    piece,
-                    flag_x,
-                    flag_y,
-                };
-                let player = self.opponent_mut(pubkey).unwrap();
-                let (attacker, move_pos) = (
-                    player.piece.take().unwrap(),
-                    player.move_pos.take().unwrap(),
-                );
-                let piece_move = compare_piece(attacker, target, move_pos);
+                GameServiceMsg::Turn(reply_tx) => {
+                    _ = reply_tx.send(TurnResult {
+                        turn: self.cur_player,
+                        move_number: self.moves,
+                    });
+                }
 
-                self.cur_player = pubkey;
-                let msg: Message = GameMessage::MoveResult(piece_move).try_into().unwrap();
-                _ = player_tx.send(msg.clone()).await;
-                _ = opp_tx.send(msg).await;
+                GameServiceMsg::Audit(reply_tx) => {
+                    _ = reply_tx.send(self.audit_log.iter().cloned().collect());
+                }
+
+                GameServiceMsg::GameMessage(pubkey, GameMessage::Ping { nonce }) => {
+                    _ = self.send(pubkey, GameMessage::Pong { nonce }).await;
+                }
+
+                GameServiceMsg::GameMessage(pubkey, GameMessage::Typing { .. }) => {
+                    self.relay_typing(pubkey).await;
+                }
+
+                // chat goes straight to the opponent, whatever the turn or game state
+                GameServiceMsg::GameMessage(pubkey, GameMessage::Chat { text, .. }) => {
+                    if text.chars().count() > CHAT_MAX_CHARS {
+                        warn!(
+                            "[{}] chat from {} too long, dropped",
+                            game_id,
+                            short_addr(&pubkey)
+                        );
+                        self.refuse(pubkey, "chat message too long").await;
+                        continue;
+                    }
+                    if let Some(opp_pubkey) = self.opponent(pubkey).map(|opp| opp.pubkey) {
+                        _ = self
+                            .send(opp_pubkey, GameMessage::Chat { game_id, text })
+                            .await;
+                    }
+                }
+
+                GameServiceMsg::GameMessage(pubkey, GameMessage::RequestRematch { .. }) => {
+                    if self.request_rematch(pubkey).await {
+                        break;
+                    }
+                }
+
+                GameServiceMsg::GameMessage(pubkey, GameMessage::Requeue { .. }) => {
+                    self.requeue(pubkey).await;
+                }
+
+                GameServiceMsg::Matched(pubkey, new_tx) => {
+                    if self.matched(pubkey, new_tx).await {
+                        break;
+                    }
+                }
+
+                GameServiceMsg::GameMessage(pubkey, msg) => {
+                    // an offline opponent doesn't hold the game up, the player to act
+                    // plays on against the clock
+                    if let Err(e) = self.process_player_message(msg, pubkey).await {
+                        error!(
+                            "process player:{} message, error:{:?}",
+                            short_addr(&pubkey),
+                            e
+                        );
+                    }
+                }
             }
-            _ => {}
         }
-        Ok(())
+
+        self.forward(rx).await;
+        self.remove_spill();
+        info!("[{}] game service stopped", game_id);
     }
 
-    fn opponent(&self, player: Address<Testnet3>) -> Option<&Player> {
-        if self.players.0.pubkey == player {
-            Some(&self.players.1)
-        } else if self.players.1.pubkey == player {
-            Some(&self.players.0)
-        } else {
-            None
+    // once both players ask within REMATCH_TIMEOUT a new game is started, and
+    // their sockets are handed to it. returns true once they are
+    async fn request_rematch(&mut self, pubkey: Address<Testnet3>) -> bool {
+        let game_id = self.game_id;
+        if !self.game_over {
+            let msg = GameMessage::Error {
+                game_id,
+                message: "the game is not over".into(),
+            };
+            _ = self.send(pubkey, msg).await;
+            return false;
         }
-    }
+        let Some(opp_pubkey) = self.opponent(pubkey).map(|opp| opp.pubkey) else {
+            return false;
+        };
+        if self.requeued.contains(&pubkey) || self.left(opp_pubkey) {
+            self.refuse(pubkey, "a player went back to the match queue")
+                .await;
+            return false;
+        }
+        let agreed = self.rematch_request.is_some_and(|(requester, at)| {
+            requester == opp_pubkey && at.elapsed() < REMATCH_TIMEOUT
+        });
+        if !agreed {
+            self.rematch_request = Some((pubkey, Instant::now()));
+            _ = self
+                .send(opp_pubkey, GameMessage::RequestRematch { game_id })
+                .await;
+            return false;
+        }
+        self.rematch_request = None;
 
-    fn opponent_mut(&mut self, player: Address<Testnet3>) -> Option<&mut Player> {
-        if self.players.0.pubkey == player {
-            Some(&mut self.players.1)
-        } else if self.players.1.pubkey == player {
-            Some(&mut self.players.0)
+        // whoever moved second now moves first
+        let first_mover = self
+            .coin_flip
+            .as_ref()
+            .map(CoinFlip::starter)
+            .unwrap_or(self.players.0.pubkey);
+        let (first, second) = if first_mover == self.players.0.pubkey {
+            (self.players.1.pubkey, self.players.0.pubkey)
         } else {
-            None
+            (self.players.0.pubkey, self.players.1.pubkey)
+        };
+        let (reply_tx, reply_rx) = oneshot::channel();
+        _ = self.events_tx.send(GameEvent::Rematch(Rematch {
+            game_id,
+            first,
+            second,
+            reply: reply_tx,
+        }));
+        let Ok((new_game_id, new_tx)) = reply_rx.await else {
+            let msg = GameMessage::Error {
+                game_id,
+                message: "rematch failed".into(),
+            };
+            self.broadcast(msg).await;
+            return false;
+        };
+        info!("[{}] rematch started as game {}", game_id, new_game_id);
+
+        // the new game sends each socket its Role
+        for pubkey in [self.players.0.pubkey, self.players.1.pubkey] {
+            self.hand_off(pubkey, &new_tx);
         }
+        true
     }
 
-    /*
-       fn player(&self, player: Address<Testnet3>) -> Option<&Player> {
-           if self.players.0.pubkey == player {
-               Some(&self.players.0)
-           } else if self.players.1.pubkey == player {
-               Some(&self.players.1)
-           } else {
-               None
-           }
-       }
-    */
-    fn player_mut(&mut self, player: Address<Testnet3>) -> Option<&mut Player> {
-        if self.players.0.pubkey == player {
-            Some(&mut self.players.0)
-        } else if self.players.1.pubkey == player {
-            Some(&mut self.players.1)
-        } else {
-            None
+    // back to the match queue, on this socket. the game stays up while the
+    // queue looks for an opponent, at most as long as a lobby waits
+    async fn requeue(&mut self, pubkey: Address<Testnet3>) {
+        if !self.game_over {
+            self.refuse(pubkey, "the game is not over").await;
+            return;
         }
+        if self.requeued.contains(&pubkey) {
+            return;
+        }
+        info!(
+            "[{}] {} back to the match queue",
+            self.game_id,
+            short_addr(&pubkey)
+        );
+        self.requeued.push(pubkey);
+        if self
+            .rematch_request
+            .is_some_and(|(requester, _)| requester == pubkey)
+        {
+            self.rematch_request = None;
+        }
+        self.close_at = Some(Instant::now() + self.timeouts.lobby_ttl);
+        _ = self
+            .events_tx
+            .send(GameEvent::Requeue(self.game_id, pubkey));
+    }
+
+    // the match queue is done with a requeued player: their socket moves to
+    // the game it found, or stays here when it found none. returns true once
+    // nobody is left
+    async fn matched(
+        &mut self,
+        pubkey: Address<Testnet3>,
+        new_tx: Option<GameServiceSender>,
+    ) -> bool {
+        if !self.requeued.contains(&pubkey) {
+            return false;
+        }
+        self.requeued.retain(|requeued| *requeued != pubkey);
+        let Some(new_tx) = new_tx else {
+            self.refuse(pubkey, "no game found in the match queue")
+                .await;
+            return false;
+        };
+        self.hand_off(pubkey, &new_tx);
+        self.requeued.is_empty() && self.players.0.conn.is_none() && self.players.1.conn.is_none()
+    }
+
+    // gone for good: back in the match queue or already in another game
+    fn left(&self, pubkey: Address<Testnet3>) -> bool {
+        self.requeued.contains(&pubkey) || self.handed_off.iter().any(|(p, ..)| *p == pubkey)
+    }
+
+    fn hand_off(&mut self, pubkey: Address<Testnet3>, new_tx: &GameServiceSender) {
+        let Some(player) = self.player_mut(pubkey) else {
+            return;
+        };
+        let Some(conn) = player.conn.take() else {
+            return;
+        };
+        player.state = PlayerState::Disconnected;
+        self.handed_off.push((pubkey, conn.id, new_tx.clone()));
+        _ = new_tx.send(GameServiceMsg::PlayerConnected(conn));
+    }
+
+    // what a socket handed to another game sends is passed on to that game,
+    // the rest is this game's own
+    fn pass_on(&mut self, data: GameServiceMsg) -> Option<GameServiceMsg> {
+        let pubkey = match &data {
+            GameServiceMsg::PlayerDisconnected(pubkey, _)
+            | GameServiceMsg::Heartbeat(pubkey, _)
+            | GameServiceMsg::BinaryFrame(pubkey)
+            | GameServiceMsg::GameMessage(pubkey, _) => *pubkey,
+            _ => return Some(data),
+        };
+        let Some(i) = self.handed_off.iter().position(|(p, ..)| *p == pubkey) else {
+            return Some(data);
+        };
+        let (_, conn_id, new_tx) = &self.handed_off[i];
+        let closed = matches!(data, GameServiceMsg::PlayerDisconnected(_, id) if id == *conn_id);
+        _ = new_tx.send(data);
+        if closed {
+            self.handed_off.remove(i);
+        }
+        None
+    }
+
+    // the sockets handed to other games still report to this one, what they
+    // send is passed on until they are all closed. a player still here is
+    // told the game is closed and let go
+    async fn forward(&mut self, mut rx: UnboundedReceiver<GameServiceMsg>) {
+        if self.handed_off.is_empty() {
+            return;
+        }
+        for pubkey in [self.players.0.pubkey, self.players.1.pubkey] {
+            if self.player_mut(pubkey).is_some_and(|p| p.conn.is_some()) {
+                self.refuse(pubkey, "the game is closed").await;
+            }
+            if let Some(conn) = self.player_mut(pubkey).and_then(|p| p.conn.take()) {
+                _ = conn.exit_signal.send(()).await;
+            }
+        }
+        while !self.handed_off.is_empty() {
+            let Some(data) = rx.recv().await else {
+                break;
+            };
+            let Some(data) = self.pass_on(data) else {
+                continue;
+            };
+            match data {
+                GameServiceMsg::Replay(reply_tx) => {
+                    _ = reply_tx.send(self.replay_moves());
+                }
+                GameServiceMsg::Turn(reply_tx) => {
+                    _ = reply_tx.send(TurnResult {
+                        turn: self.cur_player,
+                        move_number: self.moves,
+                    });
+                }
+                GameServiceMsg::Audit(reply_tx) => {
+                    _ = reply_tx.send(self.audit_log.iter().cloned().collect());
+                }
+                GameServiceMsg::Shutdown(_) => break,
+                // a login to this game after the hand off has nowhere to go
+                GameServiceMsg::PlayerConnected(conn) => {
+                    _ = conn.exit_signal.send(()).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn replay_moves(&self) -> Vec<PieceMove> {
+        let mut history = self.read_spilled();
+        history.extend(self.history.iter().cloned());
+        history
+            .into_iter()
+            .filter_map(|msg| match msg {
+                GameMessage::MoveResult(piece_move) => Some(piece_move),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // waits for the next message, flushing throttled viewer counts on the way.
+    // gives up once both players have stayed disconnected for the lobby ttl
+    async fn recv(&mut self, rx: &mut UnboundedReceiver<GameServiceMsg>) -> Option<GameServiceMsg> {
+        loop {
+            if self.players.0.state != PlayerState::Disconnected
+                || self.players.1.state != PlayerState::Disconnected
+            {
+                self.abandoned_at = None;
+            } else if self.abandoned_at.is_none() {
+                self.abandoned_at = Some(Instant::now() + self.timeouts.lobby_ttl);
+            }
+
+            let clock_at = self.clock.map(|(_, at)| at);
+            let deadline = [
+                self.abandoned_at,
+                self.spectators_flush_at,
+                clock_at,
+                self.close_at,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let Some(deadline) = deadline else {
+                return rx.recv().await;
+            };
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(data) => return data,
+                Err(_) if self.spectators_flush_at.is_some_and(|at| at <= deadline) => {
+                    self.send_spectators().await;
+                }
+                Err(_) if clock_at.is_some_and(|at| at <= deadline) => {
+                    self.clock_expired().await;
+                }
+                Err(_) if self.close_at.is_some_and(|at| at <= deadline) => {
+                    info!("[{}] no rematch, closing game", self.game_id);
+                    return None;
+                }
+                Err(_) => {
+                    info!("[{}] both players gone, closing game", self.game_id);
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn spectators_changed(&mut self) {
+        match self.spectators_sent_at {
+            Some(at) if at.elapsed() < SPECTATORS_THROTTLE => {
+                self.spectators_flush_at = Some(at + SPECTATORS_THROTTLE);
+            }
+            _ => self.send_spectators().await,
+        }
+    }
+
+    // puts `player` on the clock, a zero limit leaves the clock off
+    async fn start_clock(&mut self, player: Address<Testnet3>, limit: Duration) {
+        if limit.is_zero() || self.game_over {
+            return;
+        }
+        self.clock = Some((player, Instant::now() + limit));
+        self.broadcast(GameMessage::Clock {
+            game_id: self.game_id,
+            player,
+            remaining_ms: limit.as_millis() as u64,
+        })
+        .await;
+    }
+
+    async fn clock_expired(&mut self) {
+        let Some((player, _)) = self.clock.take() else {
+            return;
+        };
+        warn!("[{}] {} ran out of time", self.game_id, short_addr(&player));
+        let winner = self.opponent(player).map(|opp| opp.pubkey);
+        self.end_game(winner, GameOverReason::Timeout).await;
+    }
+
+    // catches a player who reconnects mid-game up on the turn, and on the
+    // opponent's move if it is waiting for this player's whisper
+    async fn resume(&mut self, pubkey: Address<Testnet3>) {
+        let msg = GameMessage::GameStart {
+            game_id: self.game_id,
+            turn: self.cur_player,
+            coin_flip: self.coin_flip.clone(),
+        };
+        _ = self.send(pubkey, msg).await;
+        if let Some((player, at)) = self.clock {
+            let msg = GameMessage::Clock {
+                game_id: self.game_id,
+                player,
+                remaining_ms: at.saturating_duration_since(Instant::now()).as_millis() as u64,
+            };
+            _ = self.send(pubkey, msg).await;
+        }
+        if self.cur_player == pubkey {
+            return;
+        }
+        let pending = self.opponent(pubkey).and_then(|opp| opp.move_pos.clone());
+        if let Some(move_pos) = pending {
+            _ = self.send(pubkey, GameMessage::PiecePos(move_pos)).await;
+        }
+    }
+
+    // passed on at most once per TYPING_THROTTLE per player, never recorded
+    async fn relay_typing(&mut self, pubkey: Address<Testnet3>) {
+        let Some(player) = self.player_mut(pubkey) else {
+            return;
+        };
+        if matches!(player.typing_sent_at, Some(at) if at.elapsed() < TYPING_THROTTLE) {
+            return;
+        }
+        player.typing_sent_at = Some(Instant::now());
+
+        let msg = GameMessage::Typing {
+            game_id: self.game_id,
+            player: Some(pubkey),
+        };
+        if let Some(opp_pubkey) = self.opponent(pubkey).map(|opp| opp.pubkey) {
+            _ = self.send(opp_pubkey, msg.clone()).await;
+        }
+        let msg: Message = msg.try_into().unwrap();
+        for (_, ws_tx) in self.spectators.iter_mut() {
+            _ = timeout(self.timeouts.send, ws_tx.send(msg.clone())).await;
+        }
+    }
+
+    // tells players and spectators how many spectators are watching
+    async fn send_spectators(&mut self) {
+        self.spectators_sent_at = Some(Instant::now());
+        self.spectators_flush_at = None;
+        let msg = GameMessage::Spectators {
+            game_id: self.game_id,
+            count: self.spectators.len() as u64,
+        };
+
+        let (player1, player2) = (self.players.0.pubkey, self.players.1.pubkey);
+        _ = self.send(player1, msg.clone()).await;
+        _ = self.send(player2, msg.clone()).await;
+        let msg: Message = msg.try_into().unwrap();
+        for (_, ws_tx) in self.spectators.iter_mut() {
+            _ = timeout(self.timeouts.send, ws_tx.send(msg.clone())).await;
+        }
+    }
+
+    async fn process_player_message(
+        &mut self,
+        msg: GameMessage,
+        pubkey: Address<Testnet3>,
+    ) -> eyre::Result<()> {
+        let game_id = self.game_id;
+        let opp_pubkey = self
+            .opponent(pubkey)
+            .map(|opp| opp.pubkey)
+            .ok_or_else(|| eyre!("{} not in game", pubkey))?;
+        if self.game_over
+            && matches!(
+                msg,
+                GameMessage::Ready { .. }
+                    | GameMessage::Move { .. }
+                    | GameMessage::Whisper { .. }
+                    | GameMessage::OfferDraw { .. }
+                    | GameMessage::AcceptDraw { .. }
+                    | GameMessage::DeclineDraw { .. }
+            )
+        {
+            warn!(
+                "[{}] game over, refuse {} from {}",
+                game_id,
+                msg,
+                short_addr(&pubkey)
+            );
+            self.refuse(pubkey, "the game is over").await;
+            return Ok(());
+        }
+        if self.started_at.is_none()
+            && matches!(
+                msg,
+                GameMessage::Move { .. }
+                    | GameMessage::Whisper { .. }
+                    | GameMessage::OfferDraw { .. }
+                    | GameMessage::AcceptDraw { .. }
+            )
+        {
+            self.refuse(pubkey, "the game has not started").await;
+            return Ok(());
+        }
+        match msg {
+            GameMessage::Ready { commitment, .. } => {
+                if self.started_at.is_some() {
+                    self.refuse(pubkey, "the game has already started").await;
+                    return Ok(());
+                }
+                if commitment.len() > COMMITMENT_MAX_LEN {
+                    self.refuse(pubkey, "commitment too long").await;
+                    return Ok(());
+                }
+                let player = self.player_mut(pubkey).unwrap();
+                player.state = PlayerState::Ready;
+                player.commitment = commitment;
+
+                if matches!(self.opponent(pubkey), Some(opp) if opp.state == PlayerState::Ready) {
+                    if self.random_turn {
+                        let (p1, p2) = &self.players;
+                        let flip = CoinFlip::new(
+                            game_id,
+                            (p1.pubkey, p1.commitment.clone()),
+                            (p2.pubkey, p2.commitment.clone()),
+                        );
+                        self.cur_player = flip.starter();
+                        self.coin_flip = Some(flip);
+                    }
+                    self.metrics.game_started();
+                    self.started_at = Some(Instant::now());
+                    self.broadcast(GameMessage::GameStart {
+                        game_id,
+                        turn: self.cur_player,
+                        coin_flip: self.coin_flip.clone(),
+                    })
+                    .await;
+                    self.start_clock(self.cur_player, self.timeouts.turn).await;
+                }
+            }
+            GameMessage::Move {
+                piece,
+                x,
+                y,
+                target_x,
+                target_y,
+                flag_x,
+                flag_y,
+            } => {
+                if self.cur_player != pubkey {
+                    warn!("[{}] not {} turn", game_id, short_addr(&pubkey));
+                    self.refuse(pubkey, "it is not your turn").await;
+                    return Ok(());
+                };
+
+                if piece == Piece::Opponent {
+                    warn!(
+                        "[{}] {} moved a placeholder piece",
+                        game_id,
+                        short_addr(&pubkey)
+                    );
+                    self.refuse(pubkey, "not a real piece").await;
+                    return Ok(());
+                }
+                if !piece.is_movable() {
+                    warn!("[{}] {:?} can not move", game_id, piece);
+                    self.refuse(pubkey, "this piece can not move").await;
+                    return Ok(());
+                }
+                if !piece.flag_pos_valid(flag_x, flag_y) {
+                    warn!(
+                        "[{}] {:?} moved with flag {:?},{:?}",
+                        game_id, piece, flag_x, flag_y
+                    );
+                    self.refuse(pubkey, "flag position goes with the field marshal only")
+                        .await;
+                    return Ok(());
+                }
+                let (from, to) = ((x as u64, y as u64), (target_x as u64, target_y as u64));
+                if !legal_step(piece, from, to) {
+                    warn!("[{}] illegal move {:?} -> {:?}", game_id, from, to);
+                    self.refuse(pubkey, "illegal move").await;
+                    return Ok(());
+                }
+
+                let player = self.player_mut(pubkey).unwrap();
+                if player.piece.is_some() {
+                    warn!(
+                        "[{}] player:{} has piece",
+                        game_id,
+                        short_addr(&player.pubkey)
+                    );
+                    self.refuse(pubkey, "your move is waiting for the whisper")
+                        .await;
+                    return Ok(());
+                }
+
+                player.piece = Some(PieceInfo {
+                    piece,
+                    flag_x,
+                    flag_y,
+                });
+                let move_pos = MovePos {
+                    x,
+                    y,
+                    target_x,
+                    target_y,
+                };
+                player.move_pos = Some(move_pos.clone());
+
+                // the mover gets its own move back with its seq. an offline opponent
+                // is sent the pending move again on reconnecting
+                self.broadcast(GameMessage::PiecePos(move_pos)).await;
+                self.start_clock(opp_pubkey, self.timeouts.whisper).await;
+                // playing on withdraws one's own draw offer
+                if self.draw_offer == Some(pubkey) {
+                    self.draw_offer = None;
+                }
+            }
+            GameMessage::Whisper {
+                piece,
+                flag_x,
+                flag_y,
+                ..
+            } => {
+                if self.cur_player == pubkey {
+                    warn!(
+                        "[{}] unexpect whisper from {}",
+                        game_id,
+                        short_addr(&pubkey)
+                    );
+                    // the player to move can only move, there is no passing
+                    let moved = matches!(self.player(pubkey), Some(p) if p.piece.is_some());
+                    let message = if moved {
+                        "only the defending player whispers, wait for the move result"
+                    } else {
+                        "it is your turn, send a move"
+                    };
+                    self.refuse(pubkey, message).await;
+                    return Ok(());
+                };
+                if piece == Piece::Opponent {
+                    warn!(
+                        "[{}] {} whispered a placeholder piece",
+                        game_id,
+                        short_addr(&pubkey)
+                    );
+                    self.refuse(pubkey, "not a real piece").await;
+                    return Ok(());
+                }
+                if !piece.flag_pos_valid(flag_x, flag_y) {
+                    warn!(
+                        "[{}] {:?} whispered with flag {:?},{:?}",
+                        game_id, piece, flag_x, flag_y
+                    );
+                    self.refuse(pubkey, "flag position goes with the field marshal only")
+                        .await;
+                    return Ok(());
+                }
+
+                // a piece standing in a camp can't be attacked, the move is void and
+                // the mover moves again
+                let pending = self.opponent(pubkey).and_then(|opp| opp.move_pos.clone());
+                if let Some(pos) = pending.filter(|pos| {
+                    piece != Piece::Empty && is_camp(pos.target_x as u64, pos.target_y as u64)
+                }) {
+                    warn!(
+                        "[{}] attack on camp ({},{}) refused",
+                        game_id, pos.target_x, pos.target_y
+                    );
+                    let opp = self.opponent_mut(pubkey).unwrap();
+                    (opp.piece, opp.move_pos) = (None, None);
+                    let msg = GameMessage::Error {
+                        game_id,
+                        message: "a piece in a camp can not be attacked, move again".into(),
+                    };
+                    self.broadcast(msg).await;
+                    self.start_clock(opp_pubkey, self.timeouts.turn).await;
+                    return Ok(());
+                }
+
+                let target = PieceInfo {
+                    piece,
+                    flag_x,
+                    flag_y,
+                };
+                let player = self.opponent_mut(pubkey).unwrap();
+                // a stray or repeated whisper has no move to settle, the two are
+                // always set together
+                let (Some(attacker), Some(move_pos)) =
+                    (player.piece.take(), player.move_pos.take())
+                else {
+                    warn!(
+                        "[{}] whisper from {} without a pending move",
+                        game_id,
+                        short_addr(&pubkey)
+                    );
+                    self.refuse(pubkey, "there is no move to whisper for").await;
+                    return Ok(());
+                };
+                let (attacker_piece, target_piece) = (attacker.piece, target.piece);
+                let mut piece_move = compare_piece(attacker, target, move_pos);
+                piece_move.move_number = self.moves as u32 + 1;
+                piece_move.timestamp_ms = chrono::Utc::now().timestamp_millis() as u64;
+
+                self.cur_player = pubkey;
+                self.broadcast(GameMessage::MoveResult(piece_move.clone()))
+                    .await;
+
+                // each side is told which of its own pieces died and became public
+                let (attacker_lost, defender_lost) = match piece_move.attack_result {
+                    AttackResult::Win => (false, true),
+                    AttackResult::Draw => (true, true),
+                    AttackResult::Lose => (true, false),
+                    AttackResult::SimpleMove => (false, false),
+                };
+                if attacker_lost {
+                    let msg = GameMessage::PieceRevealed {
+                        game_id,
+                        piece: attacker_piece,
+                        x: piece_move.x,
+                        y: piece_move.y,
+                    };
+                    _ = self.send(opp_pubkey, msg).await;
+                }
+                if defender_lost {
+                    let msg = GameMessage::PieceRevealed {
+                        game_id,
+                        piece: target_piece,
+                        x: piece_move.target_x,
+                        y: piece_move.target_y,
+                    };
+                    _ = self.send(pubkey, msg).await;
+                }
+
+                for (lost, owner, piece) in [
+                    (attacker_lost, opp_pubkey, attacker_piece),
+                    (defender_lost, pubkey, target_piece),
+                ] {
+                    if !lost {
+                        continue;
+                    }
+                    let player = self.player_mut(owner).unwrap();
+                    player.captured.push(piece);
+                    let msg = GameMessage::CapturedPieces {
+                        game_id,
+                        player: owner,
+                        pieces: player.captured.clone(),
+                    };
+                    self.broadcast(msg).await;
+                }
+
+                // game_winner 1 is the attacker, 2 the defender
+                let winner = match piece_move.game_winner {
+                    1 => Some(opp_pubkey),
+                    2 => Some(pubkey),
+                    _ => None,
+                };
+                if winner.is_some() {
+                    self.end_game(winner, GameOverReason::FlagCaptured).await;
+                    return Ok(());
+                }
+
+                // with only flag and landmines left a side can never move again
+                let variant = self.variant;
+                let stuck =
+                    |p: Option<&Player>| p.is_some_and(|p| movable_left(variant, &p.captured) == 0);
+                match (stuck(self.player(pubkey)), stuck(self.player(opp_pubkey))) {
+                    (true, true) => self.end_game(None, GameOverReason::Stalemate).await,
+                    // whoever can't move loses on their next turn anyway
+                    (true, false) => {
+                        self.end_game(Some(opp_pubkey), GameOverReason::NoMoves)
+                            .await
+                    }
+                    (false, true) => self.end_game(Some(pubkey), GameOverReason::NoMoves).await,
+                    (false, false) => {}
+                }
+                self.start_clock(pubkey, self.timeouts.turn).await;
+            }
+            GameMessage::OfferDraw { .. } => {
+                // offers that cross agree on the draw
+                if self.draw_offer == Some(opp_pubkey) {
+                    self.draw_offer = None;
+                    self.end_game(None, GameOverReason::Agreement).await;
+                    return Ok(());
+                }
+                // an offer nobody can see can't be answered, it is not kept
+                let sent = self
+                    .send(opp_pubkey, GameMessage::OfferDraw { game_id })
+                    .await;
+                if sent.is_err() {
+                    self.refuse(pubkey, "the opponent is offline").await;
+                    return Ok(());
+                }
+                self.draw_offer = Some(pubkey);
+            }
+            GameMessage::AcceptDraw { .. } => {
+                if self.draw_offer != Some(opp_pubkey) {
+                    warn!("[{}] no draw offer for {}", game_id, short_addr(&pubkey));
+                    self.refuse(pubkey, "there is no draw offer to accept")
+                        .await;
+                    return Ok(());
+                }
+                self.draw_offer = None;
+                self.end_game(None, GameOverReason::Agreement).await;
+            }
+            GameMessage::DeclineDraw { .. } => {
+                if self.draw_offer != Some(opp_pubkey) {
+                    self.refuse(pubkey, "there is no draw offer to decline")
+                        .await;
+                    return Ok(());
+                }
+                self.draw_offer = None;
+                _ = self
+                    .send(opp_pubkey, GameMessage::DeclineDraw { game_id })
+                    .await;
+            }
+            // end_game ignores repeats, so resigning twice is harmless
+            GameMessage::Resign { .. } => {
+                info!("[{}] {} resigned", game_id, short_addr(&pubkey));
+                self.end_game(Some(opp_pubkey), GameOverReason::Resigned)
+                    .await;
+            }
+            GameMessage::RequestResync { last_seq, .. } => {
+                let total = self.last_seq();
+                if last_seq > total {
+                    warn!(
+                        "[{}] {} resync from seq {}, only {} recorded",
+                        game_id,
+                        short_addr(&pubkey),
+                        last_seq,
+                        total
+                    );
+                    self.refuse(pubkey, "last_seq is ahead of the game").await;
+                    return Ok(());
+                }
+                // evicted messages are read back from the spill file, without one
+                // the player is sent what is left and the current state
+                let mut gone = false;
+                if last_seq < self.history_base {
+                    let spilled = self.read_spilled();
+                    if spilled.len() as u64 == self.history_base {
+                        for (seq, message) in (1..).zip(spilled).skip(last_seq as usize) {
+                            let msg = GameMessage::Sequenced {
+                                seq,
+                                message: Box::new(message),
+                            };
+                            self.send(pubkey, msg).await.wrap_err("send resync")?;
+                        }
+                    } else {
+                        warn!(
+                            "[{}] {} resync from seq {}, evicted up to {}",
+                            game_id,
+                            short_addr(&pubkey),
+                            last_seq,
+                            self.history_base
+                        );
+                        self.refuse(pubkey, "older messages are gone, the current state follows")
+                            .await;
+                        gone = true;
+                    }
+                }
+                for seq in last_seq.max(self.history_base) + 1..=total {
+                    let msg = self.sequenced(seq);
+                    self.send(pubkey, msg).await.wrap_err("send resync")?;
+                }
+                if gone {
+                    self.resume(pubkey).await;
+                }
+            }
+            // a greeting needs no answer
+            GameMessage::Hello { .. } => {}
+            msg => {
+                warn!(
+                    "[{}] unexpected {} from {}",
+                    game_id,
+                    msg,
+                    short_addr(&pubkey)
+                );
+                self.refuse(pubkey, "unexpected message").await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn end_game(&mut self, winner: Option<Address<Testnet3>>, reason: GameOverReason) {
+        if self.game_over {
+            return;
+        }
+        self.game_over = true;
+        self.clock = None;
+        self.close_at = Some(Instant::now() + REMATCH_TIMEOUT);
+        _ = self.events_tx.send(GameEvent::Finished(self.game_id));
+        self.metrics.game_finished(reason.into());
+        let summary = GameSummary {
+            game_id: self.game_id,
+            player1: self.players.0.pubkey,
+            player2: self.players.1.pubkey,
+            winner,
+            reason,
+            moves: self.moves,
+            duration_secs: self.started_at.map_or(0, |t| t.elapsed().as_secs()),
+        };
+        info!("[{}] game over: {:?}", self.game_id, summary);
+        if let Some(dir) = &self.limits.replay_dir {
+            let path = replay_path(dir, self.game_id);
+            if let Err(e) = save_game(&path, &summary, &self.replay_moves()) {
+                warn!(
+                    "[{}] save replay to {:?}, error: {:?}",
+                    self.game_id, path, e
+                );
+            }
+        }
+        self.broadcast(GameMessage::GameOver {
+            game_id: self.game_id,
+            winner,
+            reason,
+            summary: Some(summary),
+        })
+        .await;
+        self.sign_transcript();
+    }
+
+    fn audit(
+        &mut self,
+        from: Option<Address<Testnet3>>,
+        to: Option<Address<Testnet3>>,
+        msg: &GameMessage,
+    ) {
+        if self.audit_log.len() >= self.limits.audit_cap {
+            self.audit_log.pop_front();
+        }
+        self.audit_seq += 1;
+        let entry = AuditEntry {
+            seq: self.audit_seq,
+            from,
+            to,
+            message: msg.clone(),
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => {
+                let line = format!("{}\n", line);
+                self.transcript_hash = fnv1a(self.transcript_hash, line.as_bytes());
+                self.write_audit(&line);
+            }
+            Err(e) => warn!("[{}] serialize audit entry, error: {:?}", self.game_id, e),
+        }
+        self.audit_log.push_back(entry);
+    }
+
+    fn audit_path(&self, ext: &str) -> Option<PathBuf> {
+        let dir = self.limits.audit_dir.as_ref()?;
+        Some(dir.join(format!("{}.{}", self.game_id, ext)))
+    }
+
+    fn write_audit(&self, line: &str) {
+        let Some(path) = self.audit_path("audit.jsonl") else {
+            return;
+        };
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            warn!(
+                "[{}] write audit to {:?}, error: {:?}",
+                self.game_id, path, e
+            );
+        }
+    }
+
+    // the arbiter vouches for the transcript up to here, a dispute can then
+    // check the audit file against it
+    fn sign_transcript(&self) {
+        let Some(path) = self.audit_path("transcript.json") else {
+            return;
+        };
+        let message =
+            TranscriptSignature::message(self.game_id, self.audit_seq, self.transcript_hash);
+        // the nonce comes from the os on purpose, not from --rng-seed: with a
+        // known seed anyone could recover the arbiter key from the signature
+        let result = self
+            .arbiter_key
+            .sign_bytes(message.as_bytes(), &mut rand::thread_rng())
+            .and_then(|signature| {
+                let transcript = TranscriptSignature {
+                    game_id: self.game_id,
+                    entries: self.audit_seq,
+                    hash: self.transcript_hash,
+                    arbiter: self.arbiter,
+                    signature: signature.to_string(),
+                };
+                std::fs::write(&path, serde_json::to_string(&transcript)?)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!(
+                "[{}] sign transcript to {:?}, error: {:?}",
+                self.game_id, path, e
+            );
+        }
+    }
+
+    async fn send(&mut self, pubkey: Address<Testnet3>, msg: GameMessage) -> eyre::Result<()> {
+        self.audit(None, Some(pubkey), &msg);
+        let game_id = self.game_id;
+        let Some(player) = self.player_mut(pubkey) else {
+            bail!("player:{} not in game", short_addr(&pubkey));
+        };
+        let Some(conn) = player.conn.as_mut() else {
+            bail!("player:{} not connected", short_addr(&pubkey));
+        };
+        let msg: Message = msg.try_into()?;
+        let result = match timeout(self.timeouts.send, conn.ws_tx.send(msg)).await {
+            Ok(result) => result.wrap_err("send"),
+            Err(_) => Err(eyre!("send timeout")),
+        };
+
+        if result.is_ok() {
+            player.send_failures = 0;
+        } else {
+            player.send_failures += 1;
+            // dropping the conn stops its socket task, which reports the disconnect
+            if player.send_failures >= MAX_SEND_FAILURES {
+                warn!(
+                    "[{}] player:{} too slow, disconnecting",
+                    game_id,
+                    short_addr(&pubkey)
+                );
+                player.conn = None;
+            }
+        }
+        result
+    }
+
+    // tells a player why its message was not acted on
+    async fn refuse(&mut self, pubkey: Address<Testnet3>, message: &str) {
+        let msg = GameMessage::Error {
+            game_id: self.game_id,
+            message: message.into(),
+        };
+        _ = self.send(pubkey, msg).await;
+    }
+
+    // records a public message and sends it to both players with its seq
+    async fn broadcast(&mut self, msg: GameMessage) {
+        self.publish(msg).await;
+        let msg = self.sequenced(self.last_seq());
+        let (player1, player2) = (self.players.0.pubkey, self.players.1.pubkey);
+        _ = self.send(player1, msg.clone()).await;
+        _ = self.send(player2, msg).await;
+    }
+
+    async fn publish(&mut self, msg: GameMessage) {
+        if matches!(msg, GameMessage::MoveResult(_)) {
+            self.moves += 1;
+        }
+        self.history.push_back(msg);
+        // the newest message always stays, spectators are sent it below
+        while self.history.len() > self.limits.history_cap.max(1) {
+            let evicted = self.history.pop_front().unwrap();
+            self.history_base += 1;
+            self.spill(&evicted);
+        }
+        let msg: Message = self.sequenced(self.last_seq()).try_into().unwrap();
+        let count = self.spectators.len();
+        let mut spectators = Vec::with_capacity(count);
+        for (id, mut ws_tx) in self.spectators.drain(..) {
+            // a failed or stuck send means the spectator has gone away
+            if let Ok(Ok(())) = timeout(self.timeouts.send, ws_tx.send(msg.clone())).await {
+                spectators.push((id, ws_tx));
+            }
+        }
+        self.spectators = spectators;
+        if self.spectators.len() != count {
+            self.spectators_changed().await;
+        }
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.history_base + self.history.len() as u64
+    }
+
+    // a message still held in memory, history_base < seq <= last_seq
+    fn recorded(&self, seq: u64) -> &GameMessage {
+        &self.history[(seq - self.history_base - 1) as usize]
+    }
+
+    fn sequenced(&self, seq: u64) -> GameMessage {
+        GameMessage::Sequenced {
+            seq,
+            message: Box::new(self.recorded(seq).clone()),
+        }
+    }
+
+    fn spill_path(&self) -> Option<PathBuf> {
+        let dir = self.limits.spill_dir.as_ref()?;
+        Some(dir.join(format!("{}.jsonl", self.game_id)))
+    }
+
+    fn spill(&self, msg: &GameMessage) {
+        let Some(path) = self.spill_path() else {
+            return;
+        };
+        let result = serde_json::to_string(msg)
+            .wrap_err("serialize")
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+                writeln!(file, "{}", line)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("[{}] spill to {:?}, error: {:?}", self.game_id, path, e);
+        }
+    }
+
+    // the spill only serves resyncs and replays while the game is up, a
+    // finished game's replay is saved to the replay dir
+    fn remove_spill(&self) {
+        let Some(path) = self.spill_path() else {
+            return;
+        };
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                warn!("[{}] remove spill {:?}, error: {:?}", self.game_id, path, e);
+            }
+            _ => {}
+        }
+    }
+
+    // evicted messages in seq order, empty without a spill dir
+    fn read_spilled(&self) -> Vec<GameMessage> {
+        let Some(data) = self.spill_path().and_then(|path| read_to_string(path).ok()) else {
+            return vec![];
+        };
+        data.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    fn opponent(&self, player: Address<Testnet3>) -> Option<&Player> {
+        if self.players.0.pubkey == player {
+            Some(&self.players.1)
+        } else if self.players.1.pubkey == player {
+            Some(&self.players.0)
+        } else {
+            None
+        }
+    }
+
+    fn opponent_mut(&mut self, player: Address<Testnet3>) -> Option<&mut Player> {
+        if self.players.0.pubkey == player {
+            Some(&mut self.players.1)
+        } else if self.players.1.pubkey == player {
+            Some(&mut self.players.0)
+        } else {
+            None
+        }
+    }
+
+    fn player(&self, player: Address<Testnet3>) -> Option<&Player> {
+        if self.players.0.pubkey == player {
+            Some(&self.players.0)
+        } else if self.players.1.pubkey == player {
+            Some(&self.players.1)
+        } else {
+            None
+        }
+    }
+
+    fn player_mut(&mut self, player: Address<Testnet3>) -> Option<&mut Player> {
+        if self.players.0.pubkey == player {
+            Some(&mut self.players.0)
+        } else if self.players.1.pubkey == player {
+            Some(&mut self.players.1)
+        } else {
+            None
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        game_id: GameId,
+        arbiter: (PrivateKey<Testnet3>, Address<Testnet3>),
+        player1: Address<Testnet3>,
+        player2: Address<Testnet3>,
+        names: (String, String),
+        random_turn: bool,
+        variant: Variant,
+        metrics: Arc<Metrics>,
+        timeouts: Timeouts,
+        limits: Limits,
+        events_tx: UnboundedSender<GameEvent>,
+    ) -> Self {
+        GameService {
+            game_id,
+            arbiter: arbiter.1,
+            arbiter_key: arbiter.0,
+            players: (
+                Player {
+                    pubkey: player1,
+                    name: names.0,
+                    state: PlayerState::Disconnected,
+                    piece: None,
+                    move_pos: None,
+                    conn: None,
+                    captured: Vec::new(),
+                    send_failures: 0,
+                    typing_sent_at: None,
+                    commitment: String::new(),
+                },
+                Player {
+                    pubkey: player2,
+                    name: names.1,
+                    state: PlayerState::Disconnected,
+                    piece: None,
+                    move_pos: None,
+                    conn: None,
+                    captured: Vec::new(),
+                    send_failures: 0,
+                    typing_sent_at: None,
+                    commitment: String::new(),
+                },
+            ),
+            cur_player: player1,
+            variant,
+            random_turn,
+            coin_flip: None,
+            spectators: Vec::new(),
+            spectators_sent_at: None,
+            spectators_flush_at: None,
+            history: VecDeque::new(),
+            history_base: 0,
+            moves: 0,
+            started_at: None,
+            clock: None,
+            draw_offer: None,
+            rematch_request: None,
+            events_tx,
+            metrics,
+            timeouts,
+            limits,
+            game_over: false,
+            close_at: None,
+            abandoned_at: None,
+            audit_log: VecDeque::new(),
+            audit_seq: 0,
+            transcript_hash: FNV_OFFSET,
+            requeued: Vec::new(),
+            handed_off: Vec::new(),
+        }
+    }
+}
+
+// curl 'http://127.0.0.1:3000/join?pubkey=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj&access_code=123'
+// Query/Path wrappers whose rejections (e.g. a malformed aleo address) are
+// reported as AppResponse::Error like every other failure
+struct AppQuery<T>(T);
+struct AppPath<T>(T);
+
+fn rejection_response(status: StatusCode, body: String) -> Response {
+    (status, Json(AppResponse::Error(body))).into_response()
+}
+
+#[async_trait]
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for AppQuery<T> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Query::<T>::from_request_parts(parts, state)
+            .await
+            .map(|Query(t)| AppQuery(t))
+            .map_err(|e: QueryRejection| rejection_response(e.status(), e.body_text()))
+    }
+}
+
+#[async_trait]
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for AppPath<T> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Path::<T>::from_request_parts(parts, state)
+            .await
+            .map(|Path(t)| AppPath(t))
+            .map_err(|e: PathRejection| rejection_response(e.status(), e.body_text()))
+    }
+}
+
+async fn join(AppQuery(query): AppQuery<Join>, State(state): State<AppState>) -> impl IntoResponse {
+    let Join {
+        pubkey,
+        access_code,
+        random_turn,
+        name,
+        variant,
+    } = query;
+    let name = display_name(name, &pubkey);
+    let mut write_state = state.write().await;
+    write_state.purge_expired_lobbies();
+    if write_state.in_match_queue(&pubkey) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::JoinError(JoinError::AlreadyWaiting)),
+        );
+    }
+    // players already paired under this code don't block a new pair from reusing it
+    let usrs: Vec<_> = write_state
+        .user_map
+        .values()
+        .filter(|u| u.access_code == access_code && (u.game_id.is_none() || u.pubkey == pubkey))
+        .cloned()
+        .collect();
+
+    match usrs.len() {
+        2 => {
+            if usrs[0].pubkey != pubkey && usrs[1].pubkey != pubkey {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(AppResponse::JoinError(JoinError::AccessCodeUsed)),
+                )
+            } else {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(AppResponse::JoinError(JoinError::GameStarted)),
+                )
+            }
+        }
+        1 if usrs[0].pubkey == pubkey && usrs[0].game_id.is_some() => (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::JoinError(JoinError::GameStarted)),
+        ),
+        1 => {
+            // the token is only handed out with a fresh entry, never again
+            let (game_id, token) = if usrs[0].pubkey == pubkey {
+                write_state.user_map.entry(pubkey).and_modify(|u| {
+                    u.access_code = access_code;
+                    u.name = name;
+                    u.joined_at = Instant::now();
+                });
+                (PENDING_GAME_ID, None)
+            } else {
+                let token = write_state.new_token();
+                let user = User {
+                    pubkey,
+                    access_code,
+                    game_id: None,
+                    random_turn,
+                    variant,
+                    name,
+                    token: token.clone(),
+                    joined_at: Instant::now(),
+                };
+                // the lobby creator's random_turn and variant are the ones that count
+                let game_id = match write_state.start_game(&usrs[0], &user, &state) {
+                    Ok(game_id) => game_id,
+                    Err(e) => {
+                        return (
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            Json(AppResponse::JoinError(e)),
+                        )
+                    }
+                };
+                (game_id, Some(token))
+            };
+            let ticket = (game_id != PENDING_GAME_ID)
+                .then(|| write_state.issue_ticket(game_id, pubkey))
+                .flatten();
+            (
+                StatusCode::OK,
+                Json(AppResponse::JoinResult {
+                    game_id,
+                    ticket,
+                    token,
+                }),
+            )
+        }
+        0 => {
+            let token = write_state.new_token();
+            write_state.user_map.insert(
+                pubkey,
+                User {
+                    pubkey,
+                    access_code,
+                    game_id: None,
+                    random_turn,
+                    variant,
+                    name,
+                    token: token.clone(),
+                    joined_at: Instant::now(),
+                },
+            );
+            (
+                StatusCode::OK,
+                Json(AppResponse::JoinResult {
+                    game_id: PENDING_GAME_ID,
+                    ticket: None,
+                    token: Some(token),
+                }),
+            )
+        }
+        n => {
+            error!("access code {} shared by {} users", access_code, n);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AppResponse::JoinError(JoinError::Internal)),
+            )
+        }
+    }
+}
+
+// the game service never touches the app state itself, the game is forgotten
+// here once it stops. a panicked one drops its player connections, which
+// closes their sockets
+async fn watch_game_service(game_id: GameId, handle: JoinHandle<()>, state: AppState) {
+    if let Err(e) = handle.await {
+        error!("[{}] game service failed: {:?}", game_id, e);
+    }
+    state.write().await.remove_game(game_id);
+}
+
+// curl 'http://127.0.0.1:3000/lobby/create?pubkey=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj'
+async fn lobby_create(
+    AppQuery(query): AppQuery<LobbyCreate>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let LobbyCreate {
+        pubkey,
+        random_turn,
+        name,
+        variant,
+    } = query;
+    let mut write_state = state.write().await;
+    write_state.purge_expired_lobbies();
+    if matches!(write_state.user_map.get(&pubkey), Some(u) if u.game_id.is_some()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::JoinError(JoinError::GameStarted)),
+        )
+            .into_response();
+    }
+    if write_state.in_match_queue(&pubkey) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::JoinError(JoinError::AlreadyWaiting)),
+        )
+            .into_response();
+    }
+
+    let token = write_state.new_token();
+    let code = loop {
+        let code: String = (&mut write_state.rng)
+            .sample_iter(&Alphanumeric)
+            .take(LOBBY_CODE_LEN)
+            .map(char::from)
+            .collect();
+        if !write_state.user_map.values().any(|u| u.access_code == code) {
+            break code;
+        }
+    };
+    write_state.user_map.insert(
+        pubkey,
+        User {
+            pubkey,
+            access_code: code.clone(),
+            game_id: None,
+            random_turn,
+            variant,
+            name: display_name(name, &pubkey),
+            token: token.clone(),
+            joined_at: Instant::now(),
+        },
+    );
+    (
+        StatusCode::OK,
+        Json(LobbyCreated {
+            code,
+            game_id: None,
+            token,
+        }),
+    )
+        .into_response()
+}
+
+// pairs the player with whoever has waited longest, or queues them. like
+// join, a queued player polls /join/:pubkey with the token for the game id
+// curl 'http://127.0.0.1:3000/matchmake?pubkey=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj'
+async fn matchmake(
+    AppQuery(query): AppQuery<Matchmake>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Matchmake {
+        pubkey,
+        name,
+        variant,
+    } = query;
+    let mut write_state = state.write().await;
+    write_state.purge_expired_lobbies();
+    match write_state.user_map.get(&pubkey) {
+        Some(u) if u.game_id.is_some() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AppResponse::JoinError(JoinError::GameStarted)),
+            )
+        }
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AppResponse::JoinError(JoinError::AlreadyWaiting)),
+            )
+        }
+        None => {}
+    }
+    // asking again while queued keeps the place, the token is not repeated
+    if write_state.in_match_queue(&pubkey) {
+        return (
+            StatusCode::OK,
+            Json(AppResponse::JoinResult {
+                game_id: PENDING_GAME_ID,
+                ticket: None,
+                token: None,
+            }),
+        );
+    }
+
+    let token = write_state.new_token();
+    // strangers get a coin flip for the first move
+    let user = User {
+        pubkey,
+        access_code: String::new(),
+        game_id: None,
+        random_turn: true,
+        variant,
+        name: display_name(name, &pubkey),
+        token: token.clone(),
+        joined_at: Instant::now(),
+    };
+    let game_id = match write_state.enqueue(user, &state) {
+        Ok(Some(game_id)) => game_id,
+        Ok(None) => {
+            return (
+                StatusCode::OK,
+                Json(AppResponse::JoinResult {
+                    game_id: PENDING_GAME_ID,
+                    ticket: None,
+                    token: Some(token),
+                }),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(AppResponse::JoinError(e)),
+            )
+        }
+    };
+    let ticket = write_state.issue_ticket(game_id, pubkey);
+    (
+        StatusCode::OK,
+        Json(AppResponse::JoinResult {
+            game_id,
+            ticket,
+            token: Some(token),
+        }),
+    )
+}
+
+// curl 'http://127.0.0.1:3000/matchmake/cancel?pubkey=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj&token=...'
+async fn matchmake_cancel(
+    AppQuery(query): AppQuery<MatchmakeCancel>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let MatchmakeCancel { pubkey, token } = query;
+    let mut write_state = state.write().await;
+    let Some(i) = write_state
+        .match_queue
+        .iter()
+        .position(|u| u.pubkey == pubkey)
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::Error("not waiting for a match".into())),
+        );
+    };
+    if write_state.match_queue[i].token != token {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(AppResponse::Error("invalid token".into())),
+        );
+    }
+    write_state.match_queue.remove(i);
+    write_state.drop_requeued();
+    (StatusCode::OK, Json(AppResponse::Cancelled))
+}
+
+// curl 'http://127.0.0.1:3000/join/aleo12m0ks7kd78ulf4669v2maynerc3jhj2ukkxyw6mdv6rag6xw8cpqdpm4vm'
+async fn join_get(
+    pubkey: Result<Path<Address<Testnet3>>, PathRejection>,
+    AppQuery(query): AppQuery<JoinGetQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Ok(Path(pubkey)) = pubkey else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::Error("invalid aleo address".into())),
+        );
+    };
+    let state = state.read().await;
+
+    let usr = state
+        .user_map
+        .get(&pubkey)
+        .or_else(|| state.match_queue.iter().find(|u| u.pubkey == pubkey));
+    if let Some(usr) = usr {
+        let game_id = usr.game_id;
+        // anyone may poll for the game id, only the token holder gets a ticket
+        let ticket = match query.token {
+            Some(token) if token == usr.token => {
+                game_id.and_then(|game_id| state.issue_ticket(game_id, pubkey))
+            }
+            _ => None,
+        };
+        (
+            StatusCode::OK,
+            Json(AppResponse::JoinResult {
+                game_id: game_id.unwrap_or(PENDING_GAME_ID),
+                ticket,
+                token: None,
+            }),
+        )
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::Error("user not found".into())),
+        )
+    }
+}
+
+// the ticket and the player's signature over it travel in these headers,
+// not in the query string
+const TICKET_HEADER: &str = "x-ticket";
+const TICKET_SIGNATURE_HEADER: &str = "x-ticket-signature";
+
+// keeps the ticket headers out of the request trace
+async fn hide_ticket_headers<B>(mut request: Request<B>) -> Request<B> {
+    for name in [TICKET_HEADER, TICKET_SIGNATURE_HEADER] {
+        if let Some(value) = request.headers_mut().get_mut(name) {
+            value.set_sensitive(true);
+        }
+    }
+    request
+}
+
+fn ticket_headers(headers: &HeaderMap) -> Option<(&str, &str)> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    Some((header(TICKET_HEADER)?, header(TICKET_SIGNATURE_HEADER)?))
+}
+
+// websocat -H 'x-ticket: ...' -H 'x-ticket-signature: ...' 'ws://127.0.0.1:3000/game?game_id=1&player=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj'
+async fn enter_game(
+    AppQuery(query): AppQuery<EnterGame>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let EnterGame { player, game_id } = query;
+    let state = state.read().await;
+    info!("enter game");
+    // what became of the game is told before the ticket is looked at
+    let game = match state.game_to_enter(game_id, player) {
+        Ok(game) => game,
+        Err((status, e)) => {
+            return (status, Json(AppResponse::EnterGameError(e))).into_response();
+        }
+    };
+    let game_tx = game.tx.clone();
+    let last_activity = game.last_activity.clone();
+    let Some((ticket, signature)) = ticket_headers(&headers) else {
+        warn!(
+            "[{}] {} entering without a ticket",
+            game_id,
+            short_addr(&player)
+        );
+        return (StatusCode::UNAUTHORIZED, Json(AppResponse::Unauthorized)).into_response();
+    };
+    if let Err(e) = state.use_ticket(game_id, player, ticket, signature) {
+        warn!(
+            "[{}] {} entering with bad ticket: {:?}",
+            game_id,
+            short_addr(&player),
+            e
+        );
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(AppResponse::EnterGameError(e)),
+        )
+            .into_response();
+    }
+
+    let heartbeat = state.timeouts.heartbeat;
+    drop(state);
+    ws.on_upgrade(move |ws| handle_socket(ws, player, game_tx, last_activity, heartbeat))
+}
+
+async fn handle_socket(
+    ws: WebSocket,
+    pubkey: Address<Testnet3>,
+    game_tx: GameServiceSender,
+    last_activity: Arc<Mutex<Instant>>,
+    heartbeat: Duration,
+) {
+    async fn run(
+        ws: WebSocket,
+        conn_id: ConnId,
+        pubkey: Address<Testnet3>,
+        game_tx: GameServiceSender,
+        last_activity: Arc<Mutex<Instant>>,
+        heartbeat: Duration,
+    ) -> eyre::Result<()> {
+        debug!("player:{} is {}", short_addr(&pubkey), pubkey);
+        let (ws_tx, mut ws_rx) = ws.split();
+        let (tx, mut rx) = channel::<()>(1);
+        let msg = GameServiceMsg::PlayerConnected(PlayerConn {
+            id: conn_id,
+            pubkey,
+            ws_tx: Box::pin(ws_tx),
+            exit_signal: tx,
+        });
+        if let Err(e) = game_tx.send(msg) {
+            bail!("send game service, error: {:?}", e);
+        }
+
+        // a ping goes out every heartbeat, a peer that hasn't answered the
+        // previous one by then is taken as gone. 0 turns the heartbeat off
+        let mut ticker = (!heartbeat.is_zero()).then(|| tokio::time::interval(heartbeat));
+        let mut ponged = true;
+        loop {
+            let tick = async {
+                match ticker.as_mut() {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tick => {
+                    if !ponged {
+                        warn!("player:{} missed a heartbeat", short_addr(&pubkey));
+                        return Ok(());
+                    }
+                    ponged = false;
+                    _ = game_tx.send(GameServiceMsg::Heartbeat(pubkey, conn_id));
+                }
+                data = ws_rx.next() => {
+                    // heartbeats keep a connection alive, not the game
+                    match data.transpose().wrap_err("recv")? {
+                        Some(Message::Text(data)) => {
+                            debug!("ws recving from {}: {}", short_addr(&pubkey), data);
+                            let msg: GameMessage =
+                                serde_json::from_str(&data).wrap_err("deserialize")?;
+                            if !matches!(msg, GameMessage::Ping { .. }) {
+                                *last_activity.lock().unwrap() = Instant::now();
+                            }
+                            info!("ws recving from {}: {}", short_addr(&pubkey), msg);
+                            _ = game_tx.send(GameServiceMsg::GameMessage(pubkey, msg));
+                        }
+                        Some(Message::Close(frame)) => {
+                            info!("player:{} closed ws, frame: {:?}", short_addr(&pubkey), frame);
+                            return Ok(());
+                        }
+                        Some(Message::Binary(data)) => {
+                            let len = data.len();
+                            warn!("player:{} sent {} binary bytes", short_addr(&pubkey), len);
+                            _ = game_tx.send(GameServiceMsg::BinaryFrame(pubkey));
+                        }
+                        Some(Message::Pong(_)) => ponged = true,
+                        // pings are answered by the websocket library itself
+                        Some(_) => {}
+                        None => {
+                            info!("player:{} ws stream ended", short_addr(&pubkey));
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = rx.recv() => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = run(
+        ws,
+        conn_id,
+        pubkey,
+        game_tx.clone(),
+        last_activity,
+        heartbeat,
+    )
+    .await
+    {
+        error!("player:{} ws, error: {:?}", short_addr(&pubkey), e);
+    }
+    _ = game_tx.send(GameServiceMsg::PlayerDisconnected(pubkey, conn_id));
+}
+
+// curl 'http://127.0.0.1:3000/game/1/turn'
+async fn turn(
+    AppPath(game_id): AppPath<GameId>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    let Some(game) = state.game_map.get(&game_id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(AppResponse::Error("game not found".into())),
+        )
+            .into_response();
+    };
+    let game_tx = game.tx.clone();
+    drop(state);
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    _ = game_tx.send(GameServiceMsg::Turn(reply_tx));
+    match reply_rx.await {
+        Ok(turn) => (StatusCode::OK, Json(turn)).into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(AppResponse::Error("game service stopped".into())),
+        )
+            .into_response(),
+    }
+}
+
+// curl 'http://127.0.0.1:3000/admin/game/1/audit?token=...'
+async fn audit(
+    AppPath(game_id): AppPath<GameId>,
+    AppQuery(query): AppQuery<AdminQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    if state.admin_token.as_deref() != Some(query.token.as_str()) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(AppResponse::Error("forbidden".into())),
+        )
+            .into_response();
+    }
+    let Some(game) = state.game_map.get(&game_id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(AppResponse::Error("game not found".into())),
+        )
+            .into_response();
+    };
+    let game_tx = game.tx.clone();
+    drop(state);
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    _ = game_tx.send(GameServiceMsg::Audit(reply_tx));
+    match reply_rx.await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(AppResponse::Error("game service stopped".into())),
+        )
+            .into_response(),
+    }
+}
+
+// a finished game's players, kept aside for a rematch
+struct FinishedGame {
+    at: Instant,
+    users: Vec<User>,
+    last_activity: Arc<Mutex<Instant>>,
+    // still reaches the game service while it waits for a rematch
+    tx: GameServiceSender,
+}
+
+// a finished game frees its players at once, they are free to join another
+// one, and stays in game_map until its service closes. if both ask for a rematch, the new game shares the old one's activity,
+// its sockets keep bumping the old game's. one asking to be requeued goes
+// back to the match queue on the old game's socket
+async fn handle_game_events(state: AppState, mut rx: UnboundedReceiver<GameEvent>) {
+    let mut finished: HashMap<GameId, FinishedGame> = HashMap::new();
+    while let Some(event) = rx.recv().await {
+        let mut write_state = state.write().await;
+        finished.retain(|_, game| game.at.elapsed() < REMATCH_TIMEOUT);
+        let rematch = match event {
+            GameEvent::Finished(game_id) => {
+                if let Some((users, game)) = write_state.finish_game(game_id) {
+                    let game = FinishedGame {
+                        at: Instant::now(),
+                        users,
+                        last_activity: game.last_activity.clone(),
+                        tx: game.tx.clone(),
+                    };
+                    finished.insert(game_id, game);
+                }
+                continue;
+            }
+            GameEvent::Requeue(game_id, pubkey) => {
+                let Some(game) = finished.get(&game_id) else {
+                    warn!("[{}] requeue too late", game_id);
+                    continue;
+                };
+                let busy = write_state.user_map.contains_key(&pubkey)
+                    || write_state.in_match_queue(&pubkey);
+                let user = game.users.iter().find(|u| u.pubkey == pubkey);
+                let (Some(user), false) = (user, busy) else {
+                    warn!("[{}] requeue of {} refused", game_id, short_addr(&pubkey));
+                    _ = game.tx.send(GameServiceMsg::Matched(pubkey, None));
+                    continue;
+                };
+                let user = User {
+                    access_code: String::new(),
+                    game_id: None,
+                    random_turn: true,
+                    joined_at: Instant::now(),
+                    ..user.clone()
+                };
+                let handoff = (game.tx.clone(), game.last_activity.clone());
+                write_state.requeued.insert(pubkey, handoff);
+                if let Err(e) = write_state.enqueue(user, &state) {
+                    warn!("[{}] requeue failed: {:?}", game_id, e);
+                    write_state.requeued.remove(&pubkey);
+                    _ = game.tx.send(GameServiceMsg::Matched(pubkey, None));
+                }
+                continue;
+            }
+            GameEvent::Rematch(rematch) => rematch,
+        };
+
+        let Some(game) = finished.remove(&rematch.game_id) else {
+            warn!("[{}] rematch too late", rematch.game_id);
+            continue;
+        };
+        let users = [rematch.first, rematch.second].map(|pubkey| {
+            game.users
+                .iter()
+                .find(|u| u.pubkey == pubkey)
+                .map(|u| User {
+                    game_id: None,
+                    random_turn: false,
+                    joined_at: Instant::now(),
+                    ..u.clone()
+                })
+        });
+        let [Some(first), Some(second)] = users else {
+            warn!("[{}] rematch players unknown", rematch.game_id);
+            continue;
+        };
+        // meanwhile either may have joined another game or lobby
+        if [&first, &second].iter().any(|u| {
+            write_state.user_map.contains_key(&u.pubkey) || write_state.in_match_queue(&u.pubkey)
+        }) {
+            warn!("[{}] rematch players already busy", rematch.game_id);
+            continue;
+        }
+        let game_id = match write_state.start_game(&first, &second, &state) {
+            Ok(game_id) => game_id,
+            Err(e) => {
+                warn!("[{}] rematch refused: {:?}", rematch.game_id, e);
+                continue;
+            }
+        };
+        let new_game = write_state.game_map.get_mut(&game_id).unwrap();
+        new_game.last_activity = game.last_activity;
+        _ = rematch.reply.send((game_id, new_game.tx.clone()));
+    }
+}
+
+// periodically shuts down games nobody has sent anything to for `idle`
+async fn reap_idle_games(state: AppState, idle: Duration) {
+    let mut interval = tokio::time::interval(REAPER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let state = state.read().await;
+        for (game_id, game) in state.game_map.iter() {
+            if game.idle_for() >= idle {
+                warn!("[{}] idle for {:?}, reaping", game_id, idle);
+                _ = game.tx.send(GameServiceMsg::Shutdown(
+                    "game closed for inactivity".into(),
+                ));
+            }
+        }
+    }
+}
+
+async fn spectate(
+    AppQuery(query): AppQuery<Spectate>,
+    State(state): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    if let Some(game) = state.game_map.get(&query.game_id) {
+        let game_tx = game.tx.clone();
+        let id = NEXT_SPECTATOR_ID.fetch_add(1, Ordering::Relaxed);
+        drop(state);
+        ws.on_upgrade(move |ws| handle_spectator_socket(ws, id, game_tx))
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::Error("game not found".into())),
+        )
+            .into_response()
+    }
+}
+
+async fn handle_spectator_socket(ws: WebSocket, id: SpectatorId, game_tx: GameServiceSender) {
+    let (ws_tx, mut ws_rx) = ws.split();
+    if let Err(e) = game_tx.send(GameServiceMsg::SpectatorConnected(id, ws_tx)) {
+        error!("send game service, error: {:?}", e);
+        return;
+    }
+
+    // spectators are read-only, just drain the socket until it closes
+    while let Some(Ok(_)) = ws_rx.next().await {}
+    _ = game_tx.send(GameServiceMsg::SpectatorDisconnected(id));
+}
+
+// curl 'http://127.0.0.1:3000/replay/1?format=text'
+async fn replay(
+    AppPath(game_id): AppPath<GameId>,
+    AppQuery(query): AppQuery<ReplayQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    let game_tx = state.game_map.get(&game_id).map(|game| game.tx.clone());
+    let replay_dir = state.limits.replay_dir.clone();
+    drop(state);
+
+    let moves = if let Some(game_tx) = game_tx {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        _ = game_tx.send(GameServiceMsg::Replay(reply_tx));
+        let Ok(moves) = reply_rx.await else {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AppResponse::Error("game service stopped".into())),
+            )
+                .into_response();
+        };
+        moves
+    } else {
+        // a game that is gone may still have been saved when it finished
+        let path = replay_dir
+            .map(|dir| replay_path(&dir, game_id))
+            .filter(|path| path.exists());
+        match path.map(|path| load_game(&path)) {
+            Some(Ok((_, moves))) => moves,
+            Some(Err(e)) => {
+                error!("[{}] load replay, error: {:?}", game_id, e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(AppResponse::Error("replay unreadable".into())),
+                )
+                    .into_response();
+            }
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(AppResponse::Error("game not found".into())),
+                )
+                    .into_response()
+            }
+        }
+    };
+
+    match query.format {
+        ReplayFormat::Json => (StatusCode::OK, Json(AppResponse::Replay(moves))).into_response(),
+        ReplayFormat::Text => (StatusCode::OK, export_text(&moves)).into_response(),
+    }
+}
+
+// curl -X POST 'http://127.0.0.1:3000/validate-board?variant=standard' -H 'content-type: application/json' -d @data/player1.json
+async fn validate_board(
+    AppQuery(query): AppQuery<ValidateBoardQuery>,
+    Json(names): Json<Vec<Vec<String>>>,
+) -> impl IntoResponse {
+    let mut violations = vec![];
+    let pieces: Vec<Vec<Piece>> = names
+        .into_iter()
+        .enumerate()
+        .map(|(y, row)| {
+            row.into_iter()
+                .enumerate()
+                .map(|(x, name)| {
+                    let name = name.trim().to_string();
+                    let piece = Piece::from(name.clone());
+                    // blank squares are empty, anything else must be a real piece
+                    if (piece == Piece::Empty && !name.is_empty()) || piece == Piece::Opponent {
+                        violations.push(SetupViolation::UnknownPiece { x, y, name });
+                    }
+                    piece
+                })
+                .collect()
+        })
+        .collect();
+
+    violations.extend(validate_setup(&pieces));
+    violations.extend(validate_composition(&pieces, query.variant));
+    (
+        StatusCode::OK,
+        Json(ValidateBoardResult {
+            ok: violations.is_empty(),
+            violations,
+        }),
+    )
+}
+
+async fn livez() -> impl IntoResponse {
+    StatusCode::OK
+}
+
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if state.read().await.ready.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+// curl 'http://127.0.0.1:3000/metrics'
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let state = state.read().await;
+    Json(state.metrics.snapshot())
+}
+
+// curl 'http://127.0.0.1:3000/rules/combat'
+async fn combat_rules() -> impl IntoResponse {
+    let info = |piece| PieceInfo {
+        piece,
+        flag_x: None,
+        flag_y: None,
+    };
+    let pieces: Vec<Piece> = (0..=12).filter_map(Piece::from_repr).collect();
+    let rules: Vec<CombatRule> = pieces
+        .iter()
+        .filter(|piece| piece.is_movable())
+        .flat_map(|&attacker| {
+            pieces.iter().map(move |&defender| {
+                let move_pos = MovePos {
+                    x: 0,
+                    y: 0,
+                    target_x: 0,
+                    target_y: 0,
+                };
+                CombatRule {
+                    attacker,
+                    defender,
+                    attack_result: compare_piece(info(attacker), info(defender), move_pos)
+                        .attack_result,
+                }
+            })
+        })
+        .collect();
+    Json(rules)
+}
+
+// curl -X POST 'http://127.0.0.1:3000/adjudicate' -H 'content-type: application/json' -d '{"attacker":11,"defender":3}'
+async fn adjudicate(Json(query): Json<Adjudicate>) -> impl IntoResponse {
+    // only a piece that can move attacks, and only a real piece is attacked
+    if !query.attacker.is_movable() || matches!(query.defender, Piece::Empty | Piece::Opponent) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AppResponse::Error("no such combat".into())),
+        )
+            .into_response();
+    }
+    let info = |piece| PieceInfo {
+        piece,
+        flag_x: None,
+        flag_y: None,
+    };
+    let move_pos = MovePos {
+        x: 0,
+        y: 0,
+        target_x: 0,
+        target_y: 0,
+    };
+    let piece_move = compare_piece(info(query.attacker), info(query.defender), move_pos);
+    Json(AdjudicateResult {
+        attack_result: piece_move.attack_result,
+    })
+    .into_response()
+}
+
+fn banner() {
+    let banner = indoc! {
+        r#"
+
+    __                    __   ____        __  __  __   
+   / /   ____ _____  ____/ /  / __ )____ _/ /_/ /_/ /__ 
+  / /   / __ `/ __ \/ __  /  / __  / __ `/ __/ __/ / _ \
+ / /___/ /_/ / / / / /_/ /  / /_/ / /_/ / /_/ /_/ /  __/
+/_____/\__,_/_/ /_/\__,_/  /_____/\__,_/\__/\__/_/\___/ 
+                                                         
+        "#
+    };
+
+    info!("{}", banner.green().bold());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    const GAME_ID: GameId = 1;
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn new_address(rng: &mut StdRng) -> Address<Testnet3> {
+        Address::try_from(PrivateKey::<Testnet3>::new(rng).unwrap()).unwrap()
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            turn: Duration::from_secs(60),
+            whisper: Duration::from_secs(30),
+            lobby_ttl: Duration::from_secs(600),
+            heartbeat: Duration::ZERO,
+            send: Duration::from_secs(1),
+        }
+    }
+
+    // an empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("land_battle_chess-{}-{}", name, std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            history_cap: 4096,
+            audit_cap: 1024,
+            spill_dir: None,
+            replay_dir: None,
+            audit_dir: None,
+        }
+    }
+
+    // an app that handles game events as in main
+    fn test_app() -> AppState {
+        let mut rng = StdRng::seed_from_u64(2);
+        let arbiter = PrivateKey::<Testnet3>::new(&mut rng).unwrap();
+        let (events_tx, events_rx) = unbounded_channel();
+        let state = App::init(arbiter, timeouts(), limits(), None, 10, Some(2), events_tx);
+        tokio::spawn(handle_game_events(state.clone(), events_rx));
+        state
+    }
+
+    // waits for the app to catch up with its game services
+    async fn until(state: &AppState, f: impl Fn(&App) -> bool) {
+        let wait = async {
+            while !f(&state.read().await) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(WAIT, wait).await.expect("app state");
+    }
+
+    // a game service driven over channels instead of websockets. in a game
+    // of its own player1 moves first, a matchmade one flips a coin
+    struct TestGame {
+        tx: GameServiceSender,
+        players: (Address<Testnet3>, Address<Testnet3>),
+    }
+
+    // what a player's socket task would see of the game
+    struct Client {
+        pubkey: Address<Testnet3>,
+        conn_id: ConnId,
+        rx: mpsc::UnboundedReceiver<Message>,
+        exit: tokio::sync::mpsc::Receiver<()>,
+    }
+
+    impl TestGame {
+        fn start(timeouts: Timeouts, limits: Limits) -> Self {
+            let mut rng = StdRng::seed_from_u64(1);
+            let arbiter = PrivateKey::<Testnet3>::new(&mut rng).unwrap();
+            let players = (new_address(&mut rng), new_address(&mut rng));
+            // nobody listens to the game's events, there is no app
+            let (events_tx, _) = unbounded_channel();
+            let service = GameService::new(
+                GAME_ID,
+                (arbiter, Address::try_from(arbiter).unwrap()),
+                players.0,
+                players.1,
+                ("p1".into(), "p2".into()),
+                false,
+                Variant::Standard,
+                Arc::new(Metrics::default()),
+                timeouts,
+                limits,
+                events_tx,
+            );
+            let (tx, rx) = unbounded_channel();
+            tokio::spawn(service.run(rx));
+            TestGame { tx, players }
+        }
+
+        // a game the app started between two players in the match queue
+        async fn matchmade(state: &AppState) -> Self {
+            let mut rng = StdRng::seed_from_u64(3);
+            let players = (new_address(&mut rng), new_address(&mut rng));
+            for pubkey in [players.0, players.1] {
+                let query = Matchmake {
+                    pubkey,
+                    name: None,
+                    variant: Variant::Standard,
+                };
+                let response = matchmake(AppQuery(query), State(state.clone()))
+                    .await
+                    .into_response();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+            let state = state.read().await;
+            let game_id = state.user_map[&players.0].game_id.unwrap();
+            TestGame {
+                tx: state.game_map[&game_id].tx.clone(),
+                players,
+            }
+        }
+
+        fn connect(&self, pubkey: Address<Testnet3>) -> Client {
+            let (ws_tx, rx) = mpsc::unbounded();
+            let (exit_signal, exit) = channel(1);
+            let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+            let conn = PlayerConn {
+                id: conn_id,
+                pubkey,
+                ws_tx: Box::pin(ws_tx.sink_map_err(axum::Error::new)),
+                exit_signal,
+            };
+            self.tx.send(GameServiceMsg::PlayerConnected(conn)).unwrap();
+            Client {
+                pubkey,
+                conn_id,
+                rx,
+                exit,
+            }
+        }
+
+        // as the socket task reports a closed websocket
+        fn disconnect(&self, client: Client) {
+            self.tx
+                .send(GameServiceMsg::PlayerDisconnected(
+                    client.pubkey,
+                    client.conn_id,
+                ))
+                .unwrap();
+        }
+
+        fn send(&self, client: &Client, msg: GameMessage) {
+            self.tx
+                .send(GameServiceMsg::GameMessage(client.pubkey, msg))
+                .unwrap();
+        }
+
+        // both players connected and ready, the game started
+        async fn ready(&self) -> (Client, Client) {
+            let mut clients = (self.connect(self.players.0), self.connect(self.players.1));
+            self.send(&clients.0, GameMessage::ready(GAME_ID, "p1"));
+            self.send(&clients.1, GameMessage::ready(GAME_ID, "p2"));
+            for client in [&mut clients.0, &mut clients.1] {
+                client
+                    .expect(|msg| matches!(msg, GameMessage::GameStart { .. }))
+                    .await;
+            }
+            clients
+        }
+    }
+
+    impl Client {
+        // the next text frame, as sent
+        async fn next(&mut self) -> GameMessage {
+            let next = async {
+                loop {
+                    match self.rx.next().await {
+                        Some(Message::Text(text)) => return serde_json::from_str(&text).unwrap(),
+                        Some(_) => continue,
+                        None => panic!("socket closed"),
+                    }
+                }
+            };
+            timeout(WAIT, next).await.expect("no message")
+        }
+
+        // skips ahead to the first message `f` takes something from, public
+        // messages are looked at without their seq
+        async fn expect_map<T>(&mut self, f: impl Fn(GameMessage) -> Option<T>) -> T {
+            loop {
+                let msg = match self.next().await {
+                    GameMessage::Sequenced { message, .. } => *message,
+                    msg => msg,
+                };
+                if let Some(value) = f(msg) {
+                    return value;
+                }
+            }
+        }
+
+        // skips ahead to the first message `f` picks
+        async fn expect(&mut self, f: impl Fn(&GameMessage) -> bool) -> GameMessage {
+            self.expect_map(|msg| f(&msg).then_some(msg)).await
+        }
+
+        // the message of the next error
+        async fn error(&mut self) -> String {
+            self.expect_map(|msg| match msg {
+                GameMessage::Error { message, .. } => Some(message),
+                _ => None,
+            })
+            .await
+        }
+
+        // the winner and the reason of the next game over
+        async fn game_over(&mut self) -> (Option<Address<Testnet3>>, GameOverReason) {
+            self.expect_map(|msg| match msg {
+                GameMessage::GameOver { winner, reason, .. } => Some((winner, reason)),
+                _ => None,
+            })
+            .await
+        }
+
+        async fn move_result(&mut self) -> PieceMove {
+            self.expect_map(|msg| match msg {
+                GameMessage::MoveResult(piece_move) => Some(piece_move),
+                _ => None,
+            })
+            .await
+        }
+    }
+
+    impl Client {
+        // the next `n` public messages with their seq, skipping the rest
+        async fn sequenced(&mut self, n: usize) -> Vec<(u64, GameMessage)> {
+            let mut messages = Vec::with_capacity(n);
+            while messages.len() < n {
+                if let GameMessage::Sequenced { seq, message } = self.next().await {
+                    messages.push((seq, *message));
+                }
+            }
+            messages
+        }
+    }
+
+    fn move_msg(piece: Piece, (x, y): (u32, u32), (target_x, target_y): (u32, u32)) -> GameMessage {
+        GameMessage::Move {
+            piece,
+            x,
+            y,
+            target_x,
+            target_y,
+            flag_x: None,
+            flag_y: None,
+        }
+    }
+
+    fn whisper(piece: Piece, (x, y): (u32, u32)) -> GameMessage {
+        GameMessage::Whisper {
+            piece,
+            x,
+            y,
+            flag_x: None,
+            flag_y: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stalemate_when_only_flags_and_landmines_are_left() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+
+        // every movable piece trades itself for its twin on the other side
+        let army: Vec<Piece> = Variant::Standard
+            .piece_counts()
+            .iter()
+            .filter(|(piece, _)| piece.is_movable())
+            .flat_map(|&(piece, count)| std::iter::repeat_n(piece, count))
+            .collect();
+        for (i, piece) in army.into_iter().enumerate() {
+            let (mover, defender, from, to) = if i % 2 == 0 {
+                (&mut p1, &mut p2, (0, 5), (0, 6))
+            } else {
+                (&mut p2, &mut p1, (0, 6), (0, 5))
+            };
+            game.send(mover, move_msg(piece, from, to));
+            defender
+                .expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+                .await;
+            game.send(defender, whisper(piece, to));
+            let piece_move = mover.move_result().await;
+            assert_eq!(piece_move.attack_result, AttackResult::Draw);
+        }
+
+        for client in [&mut p1, &mut p2] {
+            let (winner, reason) = client.game_over().await;
+            assert_eq!(winner, None);
+            assert_eq!(reason, GameOverReason::Stalemate);
+        }
+    }
+
+    #[tokio::test]
+    async fn play_goes_on_while_the_opponent_is_offline() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, p2) = game.ready().await;
+
+        game.disconnect(p2);
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        // the opponent is now on the clock for its whisper
+        let opp = game.players.1;
+        p1.expect(|msg| matches!(msg, GameMessage::Clock { player, .. } if *player == opp))
+            .await;
+
+        // and is handed the move on coming back
+        let mut p2 = game.connect(game.players.1);
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Empty, (0, 6)));
+        p1.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn whisper_settles_the_move_of_an_offline_mover() {
+        let game = TestGame::start(timeouts(), limits());
+        let (p1, mut p2) = game.ready().await;
+
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.disconnect(p1);
+        game.send(&p2, whisper(Piece::Colonel, (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
+
+        let mut p1 = game.connect(game.players.0);
+        p1.expect(|msg| matches!(msg, GameMessage::GameStart { .. }))
+            .await;
+        game.send(
+            &p1,
+            GameMessage::RequestResync {
+                game_id: GAME_ID,
+                last_seq: 0,
+            },
+        );
+        let piece_move = p1.move_result().await;
+        assert_eq!(piece_move.attack_result, AttackResult::Lose);
+    }
+
+    #[tokio::test]
+    async fn resign_and_draw_offer_with_the_opponent_offline() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, p2) = game.ready().await;
+        game.disconnect(p2);
+
+        game.send(&p1, GameMessage::OfferDraw { game_id: GAME_ID });
+        assert_eq!(p1.error().await, "the opponent is offline");
+
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        let (winner, reason) = p1.game_over().await;
+        assert_eq!(winner, Some(game.players.1));
+        assert_eq!(reason, GameOverReason::Resigned);
+    }
+
+    #[tokio::test]
+    async fn moves_after_the_flag_capture_are_refused() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+        game.send(&p1, move_msg(Piece::Engineer, (3, 10), (3, 11)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Flag, (3, 11)));
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+
+        game.send(&p2, move_msg(Piece::Major, (0, 6), (0, 5)));
+        assert_eq!(p2.error().await, "the game is over");
+
+        // talking is still allowed
+        let text = "gg".to_string();
+        game.send(
+            &p2,
+            GameMessage::Chat {
+                game_id: GAME_ID,
+                text: text.clone(),
+            },
+        );
+        let received = p1
+            .expect_map(|msg| match msg {
+                GameMessage::Chat { text: received, .. } => Some(received),
+                _ => None,
+            })
+            .await;
+        assert_eq!(received, text);
+    }
+
+    #[tokio::test]
+    async fn placeholder_pieces_are_refused() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+
+        game.send(&p1, move_msg(Piece::Opponent, (0, 5), (0, 6)));
+        assert_eq!(p1.error().await, "not a real piece");
+
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Opponent, (0, 6)));
+        assert_eq!(p2.error().await, "not a real piece");
+
+        // the move still waits for a real whisper
+        game.send(&p2, whisper(Piece::Empty, (0, 6)));
+        p1.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
     }
 
-    fn new(
-        game_id: GameId,
-        arbiter: Address<Testnet3>,
-        player1: Address<Testnet3>,
-        player2: Address<Testnet3>,
-    ) -> Self {
-        GameService {
-            game_id,
-            arbiter,
-            players: (
-                Player {
-                    pubkey: player1,
-                    state: PlayerState::Disconnected,
-                    piece: None,
-                    move_pos: None,
-                },
-                Player {
-                    pubkey: player2,
-                    state: PlayerState::Disconnected,
-                    piece: None,
-                    move_pos: None,
-                },
+    // plays one simple move, seq 1 and 2 are the GameStart and its Clock
+    // and the move adds PiecePos, Clock, MoveResult, Clock as 3 to 6
+    async fn play_one_move(game: &TestGame, p1: &mut Client, p2: &mut Client) {
+        game.send(p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(p2, whisper(Piece::Empty, (0, 6)));
+        p1.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
+    }
+
+    fn resync(last_seq: u64) -> GameMessage {
+        GameMessage::RequestResync {
+            game_id: GAME_ID,
+            last_seq,
+        }
+    }
+
+    #[tokio::test]
+    async fn players_get_public_messages_with_seq() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, _p2) = game.ready().await;
+
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        let messages = p1.sequenced(2).await;
+        assert!(matches!(messages[0], (2, GameMessage::Clock { .. })));
+        // the mover sees its own move too
+        assert!(matches!(messages[1], (3, GameMessage::PiecePos(_))));
+    }
+
+    #[tokio::test]
+    async fn resync_reads_evicted_messages_from_the_spill() {
+        let spill_dir = temp_dir("resync-spill");
+        let limits = Limits {
+            history_cap: 2,
+            spill_dir: Some(spill_dir.clone()),
+            ..limits()
+        };
+        let game = TestGame::start(timeouts(), limits);
+        let (mut p1, mut p2) = game.ready().await;
+        play_one_move(&game, &mut p1, &mut p2).await;
+
+        game.disconnect(p1);
+        let mut p1 = game.connect(game.players.0);
+        game.send(&p1, resync(1));
+        let messages = p1.sequenced(5).await;
+        let seqs: Vec<u64> = messages.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![2, 3, 4, 5, 6]);
+        assert!(matches!(messages[1].1, GameMessage::PiecePos(_)));
+        assert!(matches!(messages[3].1, GameMessage::MoveResult(_)));
+        _ = std::fs::remove_dir_all(spill_dir);
+    }
+
+    #[tokio::test]
+    async fn resync_without_a_spill_sends_the_current_state() {
+        let limits = Limits {
+            history_cap: 2,
+            ..limits()
+        };
+        let game = TestGame::start(timeouts(), limits);
+        let (mut p1, mut p2) = game.ready().await;
+        play_one_move(&game, &mut p1, &mut p2).await;
+
+        game.disconnect(p1);
+        let mut p1 = game.connect(game.players.0);
+        p1.expect(|msg| matches!(msg, GameMessage::Clock { .. }))
+            .await;
+        game.send(&p1, resync(0));
+        assert_eq!(
+            p1.error().await,
+            "older messages are gone, the current state follows"
+        );
+        let seqs: Vec<u64> = p1.sequenced(2).await.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![5, 6]);
+        let turn = p1
+            .expect_map(|msg| match msg {
+                GameMessage::GameStart { turn, .. } => Some(turn),
+                _ => None,
+            })
+            .await;
+        assert_eq!(turn, game.players.1);
+    }
+
+    #[tokio::test]
+    async fn finished_game_frees_its_players_at_once() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let (mut p1, _p2) = game.ready().await;
+        assert_eq!(state.read().await.game_map.len(), 1);
+
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        until(&state, |app| app.user_map.is_empty()).await;
+        // the game itself waits for a rematch until its service closes
+        let app = state.read().await;
+        assert!(app.game_map.values().all(|game| game.finished));
+        drop(app);
+        game.tx
+            .send(GameServiceMsg::Shutdown("test over".into()))
+            .unwrap();
+        until(&state, |app| app.game_map.is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn players_of_a_finished_game_can_play_again() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let (mut p1, _p2) = game.ready().await;
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        until(&state, |app| app.user_map.is_empty()).await;
+
+        let query = LobbyCreate {
+            pubkey: game.players.0,
+            random_turn: false,
+            name: None,
+            variant: Variant::Standard,
+        };
+        let response = lobby_create(AppQuery(query), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let query = Matchmake {
+            pubkey: game.players.1,
+            name: None,
+            variant: Variant::Standard,
+        };
+        let response = matchmake(AppQuery(query), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rematch_hands_the_sockets_to_a_new_game() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let old_id = state.read().await.user_map[&game.players.0]
+            .game_id
+            .unwrap();
+        let (mut p1, mut p2) = game.ready().await;
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        p2.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+
+        game.send(&p1, GameMessage::RequestRematch { game_id: old_id });
+        p2.expect(|msg| matches!(msg, GameMessage::RequestRematch { .. }))
+            .await;
+        game.send(&p2, GameMessage::RequestRematch { game_id: old_id });
+        for client in [&mut p1, &mut p2] {
+            let game_id = client
+                .expect_map(|msg| match msg {
+                    GameMessage::Role { game_id, .. } => Some(game_id),
+                    _ => None,
+                })
+                .await;
+            assert_ne!(game_id, old_id);
+        }
+
+        // only the new game takes a slot, the old one still answers while it
+        // passes the sockets' messages on
+        let app = state.read().await;
+        assert_eq!(app.game_map.values().filter(|g| !g.finished).count(), 1);
+        assert!(app.game_map[&old_id].finished);
+        drop(app);
+        let response = turn(AppPath(old_id), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn short_turn_timeout_forfeits_quickly() {
+        let timeouts = Timeouts {
+            turn: Duration::from_millis(100),
+            ..timeouts()
+        };
+        let game = TestGame::start(timeouts, limits());
+        let started = Instant::now();
+        let (_p1, mut p2) = game.ready().await;
+
+        let (winner, reason) = p2.game_over().await;
+        assert_eq!(winner, Some(game.players.1));
+        assert_eq!(reason, GameOverReason::Timeout);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn refused_messages_are_answered() {
+        let game = TestGame::start(timeouts(), limits());
+        let mut p1 = game.connect(game.players.0);
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        assert_eq!(p1.error().await, "the game has not started");
+        drop(p1);
+
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+        for (from_p1, msg, expected) in [
+            (
+                false,
+                move_msg(Piece::Major, (0, 6), (0, 5)),
+                "it is not your turn",
             ),
-            cur_player: player1,
+            (
+                true,
+                move_msg(Piece::Landmine, (0, 1), (0, 2)),
+                "this piece can not move",
+            ),
+            (true, move_msg(Piece::Major, (0, 5), (2, 7)), "illegal move"),
+            (
+                false,
+                whisper(Piece::Empty, (0, 6)),
+                "there is no move to whisper for",
+            ),
+            (
+                true,
+                GameMessage::AcceptDraw { game_id: GAME_ID },
+                "there is no draw offer to accept",
+            ),
+            (
+                true,
+                GameMessage::ready(GAME_ID, ""),
+                "the game has already started",
+            ),
+            (true, GameMessage::Pong { nonce: 1 }, "unexpected message"),
+        ] {
+            let client = if from_p1 { &mut p1 } else { &mut p2 };
+            game.send(client, msg);
+            assert_eq!(client.error().await, expected);
         }
     }
-}
 
-// curl 'http://127.0.0.1:3000/join?pubkey=aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj&access_code=123'
-async fn join(Query(query): Query<Join>, State(state): State<AppState>) -> impl IntoResponse {
-    let Join {
-        pubkey,
-        access_code,
-    } = query;
-    let mut write_state = state.write().await;
-    let usrs: Vec<_> = write_state
-        .user_map
-        .values()
-        .filter(|u| u.access_code == access_code)
-        .cloned()
-        .collect();
-    let arbiter = write_state.arbiter.1;
+    #[tokio::test]
+    async fn disconnect_and_reconnect_mid_game() {
+        let game = TestGame::start(timeouts(), limits());
+        let (p1, mut p2) = game.ready().await;
 
-    match usrs.len() {
-        2 => {
-            if usrs[0].pubkey != pubkey && usrs[1].pubkey != pubkey {
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(AppResponse::Error("access code used".into())),
-                )
-            } else {
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(AppResponse::Error("game started".into())),
-                )
-            }
+        game.disconnect(p1);
+        p2.expect(|msg| matches!(msg, GameMessage::OpponentDisconnected { .. }))
+            .await;
+
+        // the reconnected player is caught up on whose turn it is
+        let mut p1 = game.connect(game.players.0);
+        p1.expect(|msg| matches!(msg, GameMessage::Role { .. }))
+            .await;
+        let turn = p1
+            .expect_map(|msg| match msg {
+                GameMessage::GameStart { turn, .. } => Some(turn),
+                _ => None,
+            })
+            .await;
+        assert_eq!(turn, game.players.0);
+        let player = p1
+            .expect_map(|msg| match msg {
+                GameMessage::Clock { player, .. } => Some(player),
+                _ => None,
+            })
+            .await;
+        assert_eq!(player, game.players.0);
+
+        // and plays on to the end
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Empty, (0, 6)));
+        for client in [&mut p1, &mut p2] {
+            let piece_move = client.move_result().await;
+            assert_eq!(piece_move.attack_result, AttackResult::SimpleMove);
         }
-        1 => {
-            let game_id = if usrs[0].pubkey == pubkey {
-                write_state
-                    .user_map
-                    .entry(pubkey)
-                    .and_modify(|u| u.access_code = access_code);
-                0
+
+        game.send(&p2, move_msg(Piece::Colonel, (0, 7), (0, 6)));
+        p1.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p1, whisper(Piece::Major, (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
+
+        game.send(&p1, move_msg(Piece::Engineer, (3, 10), (3, 11)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Flag, (3, 11)));
+        for client in [&mut p1, &mut p2] {
+            let (winner, reason) = client.game_over().await;
+            assert_eq!(winner, Some(game.players.0));
+            assert_eq!(reason, GameOverReason::FlagCaptured);
+        }
+    }
+
+    #[tokio::test]
+    async fn move_numbers_increase_across_a_game() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+
+        // the two majors walk past each other down columns 0 and 4
+        let moves = [
+            (true, (0, 5), (0, 6)),
+            (false, (4, 6), (4, 5)),
+            (true, (0, 6), (0, 7)),
+            (false, (4, 5), (4, 4)),
+        ];
+        let mut numbers = vec![];
+        for (from_p1, from, to) in moves {
+            let (mover, defender) = if from_p1 {
+                (&mut p1, &mut p2)
             } else {
-                let game_id = Some(rand::random::<u64>());
-                write_state.user_map.insert(
-                    pubkey,
-                    User {
-                        pubkey,
-                        access_code,
-                        game_id,
-                    },
-                );
-                write_state
-                    .user_map
-                    .entry(usrs[0].pubkey)
-                    .and_modify(|u| u.game_id = game_id);
-
-                let game_id = game_id.unwrap_or_default();
-                let (tx, rx) = unbounded_channel();
-                let game = Game {
-                    players: (usrs[0].pubkey, pubkey),
-                    tx,
-                };
-                let game_svc = GameService::new(game_id, arbiter, usrs[0].pubkey, pubkey);
-                tokio::spawn({
-                    let state = state.clone();
-                    async {
-                        game_svc.run(rx, state).await;
-                    }
-                });
-                write_state.game_map.insert(game_id, game);
-                game_id
+                (&mut p2, &mut p1)
             };
-            (StatusCode::OK, Json(AppResponse::JoinResult { game_id }))
+            game.send(mover, move_msg(Piece::Major, from, to));
+            defender
+                .expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+                .await;
+            game.send(defender, whisper(Piece::Empty, to));
+            let piece_move = mover.move_result().await;
+            assert!(piece_move.timestamp_ms > 0);
+            numbers.push(piece_move.move_number);
         }
-        0 => {
-            write_state.user_map.insert(
-                pubkey,
-                User {
-                    pubkey,
-                    access_code,
-                    game_id: None,
-                },
-            );
-            (StatusCode::OK, Json(AppResponse::JoinResult { game_id: 0 }))
+        assert_eq!(numbers, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn moves_off_the_board_or_out_of_reach_are_refused() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+
+        for (from, to) in [
+            // off the board
+            ((0, 5), (0, 12)),
+            ((4, 5), (5, 5)),
+            // staying put
+            ((0, 5), (0, 5)),
+            // two squares off the railroad
+            ((2, 3), (2, 5)),
+            // diagonal between two ordinary squares
+            ((0, 5), (1, 6)),
+        ] {
+            game.send(&p1, move_msg(Piece::Major, from, to));
+            assert_eq!(p1.error().await, "illegal move", "{:?} -> {:?}", from, to);
         }
-        _ => unreachable!(),
+
+        // none of them took the turn, a proper step still goes through
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
     }
-}
 
-// curl 'http://127.0.0.1:3000/join/aleo12m0ks7kd78ulf4669v2maynerc3jhj2ukkxyw6mdv6rag6xw8cpqdpm4vm'
-async fn join_get(
-    Path(pubkey): Path<Address<Testnet3>>,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
-    let state = state.read().await;
+    #[tokio::test]
+    async fn stray_whisper_leaves_the_game_running() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
 
-    if let Some(usr) = state.user_map.get(&pubkey) {
-        (
-            StatusCode::OK,
-            Json(AppResponse::JoinResult {
-                game_id: usr.game_id.unwrap_or_default(),
+        // before any move was made
+        game.send(&p2, whisper(Piece::Colonel, (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::Error { .. }))
+            .await;
+
+        game.send(&p1, move_msg(Piece::Major, (0, 5), (0, 6)));
+        p2.expect(|msg| matches!(msg, GameMessage::PiecePos(_)))
+            .await;
+        game.send(&p2, whisper(Piece::Colonel, (0, 6)));
+        p1.expect(|msg| matches!(msg, GameMessage::MoveResult(_)))
+            .await;
+
+        // a repeated whisper finds the move already settled
+        game.send(&p2, whisper(Piece::Colonel, (0, 6)));
+        assert_eq!(p2.error().await, "it is your turn, send a move");
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        game.tx.send(GameServiceMsg::Turn(reply_tx)).unwrap();
+        let turn = timeout(WAIT, reply_rx).await.unwrap().unwrap();
+        assert_eq!((turn.turn, turn.move_number), (game.players.1, 1));
+    }
+
+    async fn response_json(response: Response) -> serde_json::Value {
+        use axum::body::HttpBody;
+
+        let mut body = response.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn both_players_of_a_lobby_see_the_same_game_id() {
+        let state = test_app();
+        let mut rng = StdRng::seed_from_u64(4);
+        let (a, b) = (new_address(&mut rng), new_address(&mut rng));
+        let join_as = |pubkey| {
+            let query = Join {
+                access_code: "768".into(),
+                pubkey,
+                random_turn: false,
+                name: None,
+                variant: Variant::Standard,
+            };
+            join(AppQuery(query), State(state.clone()))
+        };
+
+        let pending = response_json(join_as(a).await.into_response()).await;
+        assert_eq!(pending["JoinResult"]["game_id"], "0");
+        let joined = response_json(join_as(b).await.into_response()).await;
+        let game_id = joined["JoinResult"]["game_id"].clone();
+        assert_ne!(game_id, "0");
+
+        let query = JoinGetQuery { token: None };
+        let polled = join_get(Ok(Path(a)), AppQuery(query), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(
+            response_json(polled).await["JoinResult"]["game_id"],
+            game_id
+        );
+    }
+
+    #[tokio::test]
+    async fn spill_file_is_removed_when_the_game_stops() {
+        let spill_dir = temp_dir("spill-cleanup");
+        let limits = Limits {
+            history_cap: 2,
+            spill_dir: Some(spill_dir.clone()),
+            ..limits()
+        };
+        let game = TestGame::start(timeouts(), limits);
+        let (mut p1, mut p2) = game.ready().await;
+        play_one_move(&game, &mut p1, &mut p2).await;
+        let spill = spill_dir.join(format!("{}.jsonl", GAME_ID));
+        assert!(spill.exists());
+
+        game.tx
+            .send(GameServiceMsg::Shutdown("server restart".into()))
+            .unwrap();
+        timeout(WAIT, game.tx.closed()).await.unwrap();
+        assert!(!spill.exists());
+        _ = std::fs::remove_dir_all(spill_dir);
+    }
+
+    #[tokio::test]
+    async fn entering_a_finished_or_unknown_game() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let game_id = state.read().await.user_map[&game.players.0]
+            .game_id
+            .unwrap();
+        let stranger = new_address(&mut StdRng::seed_from_u64(5));
+
+        let app = state.read().await;
+        assert!(app.game_to_enter(game_id, game.players.0).is_ok());
+        assert!(matches!(
+            app.game_to_enter(game_id, stranger),
+            Err((StatusCode::BAD_REQUEST, EnterGameError::NotParticipant))
+        ));
+        drop(app);
+
+        let (mut p1, _p2) = game.ready().await;
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        until(&state, |app| app.user_map.is_empty()).await;
+        // back in time for a rematch
+        assert!(state
+            .read()
+            .await
+            .game_to_enter(game_id, game.players.1)
+            .is_ok());
+
+        game.tx
+            .send(GameServiceMsg::Shutdown("test over".into()))
+            .unwrap();
+        until(&state, |app| app.game_map.is_empty()).await;
+        let app = state.read().await;
+        assert!(matches!(
+            app.game_to_enter(game_id, game.players.1),
+            Err((StatusCode::GONE, EnterGameError::Finished))
+        ));
+        assert!(matches!(
+            app.game_to_enter(game_id + 1, game.players.1),
+            Err((StatusCode::NOT_FOUND, EnterGameError::NotFound))
+        ));
+        drop(app);
+
+        // nothing was saved for it without a replay dir
+        let response = replay(
+            AppPath(game_id),
+            AppQuery(ReplayQuery {
+                format: ReplayFormat::Json,
             }),
+            State(state.clone()),
         )
-    } else {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(AppResponse::Error("user not found".into())),
-        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
-}
 
-async fn enter_game(
-    Query(query): Query<EnterGame>,
-    State(state): State<AppState>,
-    ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    let EnterGame { player, game_id } = query;
-    let state = state.read().await;
-    let game = state.game_map.get(&game_id);
-    info!("enter game");
-    if let Some(game) = game {
-        {
-            if game.players.0 != player && game.players.1 != player {
-                return Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(body::boxed(body::Empty::new()))
-                    .unwrap();
+    // 1, 2, 3, ... in place of random ids
+    fn sequential_game_ids() -> GameIdGen {
+        let mut last = PENDING_GAME_ID;
+        Box::new(move |_| {
+            last += 1;
+            last
+        })
+    }
+
+    #[tokio::test]
+    async fn game_ids_follow_the_generator() {
+        let state = test_app();
+        state.write().await.next_game_id = sequential_game_ids();
+
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut game_ids = vec![];
+        for _ in 0..3 {
+            let players = [new_address(&mut rng), new_address(&mut rng)];
+            for pubkey in players {
+                let query = Matchmake {
+                    pubkey,
+                    name: None,
+                    variant: Variant::Standard,
+                };
+                let response = matchmake(AppQuery(query), State(state.clone()))
+                    .await
+                    .into_response();
+                assert_eq!(response.status(), StatusCode::OK);
             }
+            game_ids.push(state.read().await.user_map[&players[0]].game_id);
         }
-        let game_tx = game.tx.clone();
-        drop(state);
-        ws.on_upgrade(move |ws| handle_socket(ws, player, game_tx))
-    } else {
-        Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(body::boxed(body::Empty::new()))
-            .unwrap()
+        assert_eq!(game_ids, vec![Some(1), Some(2), Some(3)]);
     }
-}
 
-async fn handle_socket(ws: WebSocket, pubkey: Address<Testnet3>, game_tx: GameServiceSender) {
-    async fn run(
-        ws: WebSocket,
-        pubkey: Address<Testnet3>,
-        game_tx: GameServiceSender,
-    ) -> eyre::Result<()> {
-        let (ws_tx, mut ws_rx) = ws.split();
-        let (tx, mut rx) = channel::<()>(1);
-        let msg = GameServiceMsg::PlayerConnected(PlayerConn {
-            pubkey,
-            ws_tx,
-            exit_signal: tx,
-        });
-        if let Err(e) = game_tx.send(msg) {
-            bail!("send game service, error: {:?}", e);
+    #[tokio::test]
+    async fn matchmaking_pairs_players_of_the_same_variant() {
+        let state = test_app();
+        let mut rng = StdRng::seed_from_u64(7);
+        let players = [(); 3].map(|_| new_address(&mut rng));
+        let variants = [Variant::Mini, Variant::Standard, Variant::Mini];
+        for (pubkey, variant) in players.into_iter().zip(variants) {
+            let query = Matchmake {
+                pubkey,
+                name: None,
+                variant,
+            };
+            let response = matchmake(AppQuery(query), State(state.clone()))
+                .await
+                .into_response();
+            assert_eq!(response.status(), StatusCode::OK);
         }
 
-        loop {
-            tokio::select! {
-                Some(data) = ws_rx.next() => {
-                    let data = data.wrap_err("recv")?;
-                    if let Message::Text(data) = data {
-                        info!("ws recving {}", data);
-                        let msg: GameMessage = serde_json::from_str(&data).wrap_err("deserialize")?;
-                        _ = game_tx.send(GameServiceMsg::GameMessage(pubkey, msg));
-                    }
-                }
-                _ = rx.recv() => {
-                    return Ok(());
-                }
-            }
-        }
+        let app = state.read().await;
+        let game_id = app.user_map[&players[0]].game_id.unwrap();
+        assert_eq!(app.user_map[&players[2]].game_id, Some(game_id));
+        assert!(app.in_match_queue(&players[1]));
+        let game = TestGame {
+            tx: app.game_map[&game_id].tx.clone(),
+            players: (players[0], players[2]),
+        };
+        drop(app);
+
+        let mut client = game.connect(players[2]);
+        let variant = client
+            .expect_map(|msg| match msg {
+                GameMessage::Role { variant, .. } => Some(variant),
+                _ => None,
+            })
+            .await;
+        assert_eq!(variant, Variant::Mini);
     }
 
-    if let Err(e) = run(ws, pubkey, game_tx).await {
-        error!("player ws, error: {:?}", e);
+    #[tokio::test]
+    async fn coin_flip_runs_over_the_ready_commitments() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let game_id = state.read().await.user_map[&game.players.0]
+            .game_id
+            .unwrap();
+        let mut p1 = game.connect(game.players.0);
+        let mut p2 = game.connect(game.players.1);
+        game.send(
+            &p1,
+            GameMessage::ready(GAME_ID, "x".repeat(COMMITMENT_MAX_LEN + 1)),
+        );
+        assert_eq!(p1.error().await, "commitment too long");
+
+        game.send(&p1, GameMessage::ready(GAME_ID, "p1"));
+        game.send(&p2, GameMessage::ready(GAME_ID, "p2"));
+        let (turn, coin_flip) = p2
+            .expect_map(|msg| match msg {
+                GameMessage::GameStart {
+                    turn, coin_flip, ..
+                } => Some((turn, coin_flip)),
+                _ => None,
+            })
+            .await;
+        let coin_flip = coin_flip.unwrap();
+        assert_eq!(
+            coin_flip,
+            CoinFlip::new(
+                game_id,
+                (game.players.0, "p1".into()),
+                (game.players.1, "p2".into())
+            )
+        );
+        assert_eq!(turn, coin_flip.starter());
     }
-}
 
-fn banner() {
-    let banner = indoc! {
-        r#"
+    async fn audit_log(game: &TestGame) -> Vec<AuditEntry> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        game.tx.send(GameServiceMsg::Audit(reply_tx)).unwrap();
+        timeout(WAIT, reply_rx).await.unwrap().unwrap()
+    }
 
-    __                    __   ____        __  __  __   
-   / /   ____ _____  ____/ /  / __ )____ _/ /_/ /_/ /__ 
-  / /   / __ `/ __ \/ __  /  / __  / __ `/ __/ __/ / _ \
- / /___/ /_/ / / / / /_/ /  / /_/ / /_/ / /_/ /_/ /  __/
-/_____/\__,_/_/ /_/\__,_/  /_____/\__,_/\__/\__/_/\___/ 
-                                                         
-        "#
-    };
+    #[tokio::test]
+    async fn audit_records_every_frame_in_order() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+        game.send(&p1, GameMessage::Ping { nonce: 1 });
+        p1.expect(|msg| matches!(msg, GameMessage::Pong { .. }))
+            .await;
+        let chat = GameMessage::Chat {
+            game_id: GAME_ID,
+            text: "gl".into(),
+        };
+        game.send(&p1, chat);
+        p2.expect(|msg| matches!(msg, GameMessage::Chat { .. }))
+            .await;
+        let typing = GameMessage::Typing {
+            game_id: GAME_ID,
+            player: None,
+        };
+        game.send(&p1, typing);
+        game.send(&p1, GameMessage::Resign { game_id: GAME_ID });
+        p2.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        game.send(&p1, GameMessage::RequestRematch { game_id: GAME_ID });
+        p2.expect(|msg| matches!(msg, GameMessage::RequestRematch { .. }))
+            .await;
 
-    info!("{}", banner.green().bold());
+        let entries = audit_log(&game).await;
+        let seqs: Vec<u64> = entries.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, (1..=entries.len() as u64).collect::<Vec<_>>());
+        let sent: Vec<&GameMessage> = entries
+            .iter()
+            .filter(|entry| entry.from == Some(p1.pubkey))
+            .map(|entry| &entry.message)
+            .collect();
+        assert!(matches!(
+            sent[..],
+            [
+                GameMessage::Ready { .. },
+                GameMessage::Ping { .. },
+                GameMessage::Chat { .. },
+                GameMessage::Typing { .. },
+                GameMessage::Resign { .. },
+                GameMessage::RequestRematch { .. },
+            ]
+        ));
+        // the server's answers are recorded as going to the player
+        assert!(entries.iter().any(|entry| entry.to == Some(p1.pubkey)
+            && matches!(entry.message, GameMessage::Pong { nonce: 1 })));
+    }
+
+    #[tokio::test]
+    async fn finished_game_transcript_is_signed() {
+        let audit_dir = temp_dir("audit");
+        let limits = Limits {
+            audit_cap: 2,
+            audit_dir: Some(audit_dir.clone()),
+            ..limits()
+        };
+        let game = TestGame::start(timeouts(), limits);
+        let mut p1 = game.connect(game.players.0);
+        let mut p2 = game.connect(game.players.1);
+        let arbiter = p1
+            .expect_map(|msg| match msg {
+                GameMessage::Role { arbiter, .. } => Some(arbiter),
+                _ => None,
+            })
+            .await;
+        game.send(&p1, GameMessage::ready(GAME_ID, "p1"));
+        game.send(&p2, GameMessage::ready(GAME_ID, "p2"));
+        play_one_move(&game, &mut p1, &mut p2).await;
+        game.send(&p2, GameMessage::Resign { game_id: GAME_ID });
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        // answered after the game over is handled, the signature is written by then
+        assert_eq!(audit_log(&game).await.len(), 2);
+
+        let transcript: TranscriptSignature = serde_json::from_str(
+            &read_to_string(audit_dir.join(format!("{}.transcript.json", GAME_ID))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(transcript.arbiter, arbiter);
+        let message =
+            TranscriptSignature::message(transcript.game_id, transcript.entries, transcript.hash);
+        let signature = Signature::<Testnet3>::from_str(&transcript.signature).unwrap();
+        assert!(signature.verify_bytes(&arbiter, message.as_bytes()));
+
+        // the file holds what the ring buffer dropped, and hashes to the signed value
+        let file = read_to_string(audit_dir.join(format!("{}.audit.jsonl", GAME_ID))).unwrap();
+        let lines: Vec<&str> = file.lines().collect();
+        assert!(lines.len() as u64 >= transcript.entries);
+        let hash = lines[..transcript.entries as usize]
+            .iter()
+            .fold(FNV_OFFSET, |hash, line| {
+                fnv1a(hash, format!("{}\n", line).as_bytes())
+            });
+        assert_eq!(hash, transcript.hash);
+        let first: AuditEntry = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first.seq, 1);
+        _ = std::fs::remove_dir_all(audit_dir);
+    }
+
+    #[tokio::test]
+    async fn a_ticket_admits_one_connection() {
+        let state = test_app();
+        let mut rng = StdRng::seed_from_u64(6);
+        let player_key = PrivateKey::<Testnet3>::new(&mut rng).unwrap();
+        let player = Address::try_from(player_key).unwrap();
+        let stranger_key = PrivateKey::<Testnet3>::new(&mut rng).unwrap();
+        let sign = |key: PrivateKey<Testnet3>, ticket: &str| {
+            key.sign_bytes(ticket.as_bytes(), &mut StdRng::seed_from_u64(7))
+                .unwrap()
+                .to_string()
+        };
+
+        let app = state.read().await;
+        let ticket = app.issue_ticket(GAME_ID, player).unwrap();
+        assert!(matches!(
+            app.use_ticket(GAME_ID, player, &ticket, &sign(stranger_key, &ticket)),
+            Err(EnterGameError::InvalidSignature)
+        ));
+        assert!(matches!(
+            app.use_ticket(GAME_ID + 1, player, &ticket, &sign(player_key, &ticket)),
+            Err(EnterGameError::InvalidTicket)
+        ));
+        let signature = sign(player_key, &ticket);
+        assert!(app.use_ticket(GAME_ID, player, &ticket, &signature).is_ok());
+        assert!(matches!(
+            app.use_ticket(GAME_ID, player, &ticket, &signature),
+            Err(EnterGameError::TicketUsed)
+        ));
+    }
+
+    #[test]
+    fn ticket_is_read_from_the_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TICKET_HEADER, HeaderValue::from_static("1.sign"));
+        assert_eq!(ticket_headers(&headers), None);
+        headers.insert(TICKET_SIGNATURE_HEADER, HeaderValue::from_static("sign"));
+        assert_eq!(ticket_headers(&headers), Some(("1.sign", "sign")));
+    }
+
+    #[tokio::test]
+    async fn ticket_headers_are_hidden_from_the_trace() {
+        let request = Request::builder()
+            .header(TICKET_HEADER, "1.sign")
+            .header("user-agent", "test")
+            .body(())
+            .unwrap();
+        let request = hide_ticket_headers(request).await;
+        assert!(request.headers()[TICKET_HEADER].is_sensitive());
+        assert!(!request.headers()["user-agent"].is_sensitive());
+    }
+
+    #[tokio::test]
+    async fn declined_rematch_goes_back_to_the_match_queue() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let old_id = state.read().await.user_map[&game.players.0]
+            .game_id
+            .unwrap();
+        let (mut p1, mut p2) = game.ready().await;
+        game.send(&p1, GameMessage::Resign { game_id: old_id });
+        p2.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+
+        // p2 turns the rematch down and asks for someone else
+        game.send(&p1, GameMessage::request_rematch(old_id));
+        p2.expect(|msg| matches!(msg, GameMessage::RequestRematch { .. }))
+            .await;
+        game.send(&p2, GameMessage::requeue(old_id));
+        until(&state, |app| app.in_match_queue(&game.players.1)).await;
+        game.send(&p1, GameMessage::request_rematch(old_id));
+        assert_eq!(p1.error().await, "a player went back to the match queue");
+
+        let stranger = new_address(&mut StdRng::seed_from_u64(8));
+        let query = Matchmake {
+            pubkey: stranger,
+            name: None,
+            variant: Variant::Standard,
+        };
+        let response = matchmake(AppQuery(query), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the new game reaches p2 on the old socket
+        let (game_id, player1, player2) = p2
+            .expect_map(|msg| match msg {
+                GameMessage::Role {
+                    game_id,
+                    player1,
+                    player2,
+                    ..
+                } => Some((game_id, player1, player2)),
+                _ => None,
+            })
+            .await;
+        assert_ne!(game_id, old_id);
+        assert_eq!((player1, player2), (game.players.1, stranger));
+        assert_eq!(
+            state.read().await.user_map[&game.players.1].game_id,
+            Some(game_id)
+        );
+        // and what p2 sends goes to the new game
+        game.send(&p2, GameMessage::ready(game_id, "p2"));
+        game.send(&p2, GameMessage::ping(9));
+        p2.expect(|msg| matches!(msg, GameMessage::Pong { nonce: 9 }))
+            .await;
+        let app = state.read().await;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        app.game_map[&game_id]
+            .tx
+            .send(GameServiceMsg::Audit(reply_tx))
+            .unwrap();
+        drop(app);
+        let entries = timeout(WAIT, reply_rx).await.unwrap().unwrap();
+        assert!(entries
+            .iter()
+            .any(|entry| entry.from == Some(game.players.1)
+                && matches!(entry.message, GameMessage::Ready { .. })));
+
+        // the new game goes by the old socket's activity too
+        let app = state.read().await;
+        let handed = &app.game_map[&game_id].handed_activity;
+        assert!(handed
+            .iter()
+            .any(|activity| Arc::ptr_eq(activity, &app.game_map[&old_id].last_activity)));
+        drop(app);
+
+        // p1 is let go when the old game closes
+        game.tx
+            .send(GameServiceMsg::Shutdown("closed".into()))
+            .unwrap();
+        assert_eq!(p1.error().await, "closed");
+        assert_eq!(p1.error().await, "the game is closed");
+        timeout(WAIT, p1.exit.recv()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requeued_player_hears_when_the_queue_gives_up() {
+        let state = test_app();
+        let game = TestGame::matchmade(&state).await;
+        let (mut p1, _p2) = game.ready().await;
+        game.send(&p1, GameMessage::resign(GAME_ID));
+        p1.expect(|msg| matches!(msg, GameMessage::GameOver { .. }))
+            .await;
+        game.send(&p1, GameMessage::requeue(GAME_ID));
+        until(&state, |app| app.in_match_queue(&game.players.0)).await;
+
+        let token = state.read().await.match_queue[0].token.clone();
+        let query = MatchmakeCancel {
+            pubkey: game.players.0,
+            token,
+        };
+        let response = matchmake_cancel(AppQuery(query), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(p1.error().await, "no game found in the match queue");
+        assert!(state.read().await.requeued.is_empty());
+    }
+
+    #[test]
+    fn game_is_busy_while_a_requeued_socket_is() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let stale = Instant::now() - Duration::from_secs(60);
+        let mut game = Game {
+            players: (new_address(&mut rng), new_address(&mut rng)),
+            tx: unbounded_channel().0,
+            last_activity: Arc::new(Mutex::new(stale)),
+            finished: false,
+            handed_activity: vec![Arc::new(Mutex::new(stale))],
+        };
+        assert!(game.idle_for() >= Duration::from_secs(60));
+        // the requeued socket still bumps its old game's activity
+        game.handed_activity
+            .push(Arc::new(Mutex::new(Instant::now())));
+        assert!(game.idle_for() < Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn adjudicate_refuses_pieces_that_cannot_fight() {
+        let query = Adjudicate {
+            attacker: Piece::General,
+            defender: Piece::Landmine,
+        };
+        let response = adjudicate(Json(query)).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        for (attacker, defender) in [
+            (Piece::Landmine, Piece::Engineer),
+            (Piece::Flag, Piece::Engineer),
+            (Piece::Empty, Piece::Engineer),
+            (Piece::Opponent, Piece::Engineer),
+            (Piece::General, Piece::Empty),
+            (Piece::General, Piece::Opponent),
+        ] {
+            let query = Adjudicate { attacker, defender };
+            let response = adjudicate(Json(query)).await.into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", attacker);
+        }
+    }
+
+    #[tokio::test]
+    async fn crossing_draw_offers_agree_on_a_draw() {
+        let game = TestGame::start(timeouts(), limits());
+        let (mut p1, mut p2) = game.ready().await;
+        game.send(&p1, GameMessage::OfferDraw { game_id: GAME_ID });
+        p2.expect(|msg| matches!(msg, GameMessage::OfferDraw { .. }))
+            .await;
+        game.send(&p2, GameMessage::OfferDraw { game_id: GAME_ID });
+        for client in [&mut p1, &mut p2] {
+            let (winner, reason) = client.game_over().await;
+            assert_eq!(winner, None);
+            assert_eq!(reason, GameOverReason::Agreement);
+        }
+    }
 }
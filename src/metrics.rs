@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::types::GameOverReason;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameOutcome {
+    Win,
+    Draw,
+    Resign,
+    Timeout,
+}
+
+impl From<GameOverReason> for GameOutcome {
+    fn from(reason: GameOverReason) -> Self {
+        match reason {
+            GameOverReason::FlagCaptured
+            | GameOverReason::Forfeit
+            | GameOverReason::Cheat
+            | GameOverReason::NoMoves => GameOutcome::Win,
+            GameOverReason::Agreement | GameOverReason::Repetition | GameOverReason::Stalemate => {
+                GameOutcome::Draw
+            }
+            GameOverReason::Resigned => GameOutcome::Resign,
+            GameOverReason::Timeout => GameOutcome::Timeout,
+        }
+    }
+}
+
+// counters are shared by every game service, increments saturate instead of wrapping
+#[derive(Debug)]
+pub struct Metrics {
+    start_time: Instant,
+    games_started: AtomicU64,
+    games_won: AtomicU64,
+    games_drawn: AtomicU64,
+    games_resigned: AtomicU64,
+    games_timed_out: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub uptime_secs: u64,
+    pub games_started: u64,
+    pub games_finished: u64,
+    pub games_won: u64,
+    pub games_drawn: u64,
+    pub games_resigned: u64,
+    pub games_timed_out: u64,
+    // per hour of uptime
+    pub games_started_rate: f64,
+    pub games_finished_rate: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            start_time: Instant::now(),
+            games_started: AtomicU64::new(0),
+            games_won: AtomicU64::new(0),
+            games_drawn: AtomicU64::new(0),
+            games_resigned: AtomicU64::new(0),
+            games_timed_out: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn game_started(&self) {
+        saturating_inc(&self.games_started);
+    }
+
+    pub fn game_finished(&self, outcome: GameOutcome) {
+        let counter = match outcome {
+            GameOutcome::Win => &self.games_won,
+            GameOutcome::Draw => &self.games_drawn,
+            GameOutcome::Resign => &self.games_resigned,
+            GameOutcome::Timeout => &self.games_timed_out,
+        };
+        saturating_inc(counter);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let uptime = self.start_time.elapsed();
+        let games_started = self.games_started.load(Ordering::Relaxed);
+        let games_won = self.games_won.load(Ordering::Relaxed);
+        let games_drawn = self.games_drawn.load(Ordering::Relaxed);
+        let games_resigned = self.games_resigned.load(Ordering::Relaxed);
+        let games_timed_out = self.games_timed_out.load(Ordering::Relaxed);
+        let games_finished = games_won
+            .saturating_add(games_drawn)
+            .saturating_add(games_resigned)
+            .saturating_add(games_timed_out);
+
+        let hours = uptime.as_secs_f64() / 3600.0;
+        let rate = |count: u64| {
+            if hours > 0.0 {
+                count as f64 / hours
+            } else {
+                0.0
+            }
+        };
+        MetricsSnapshot {
+            uptime_secs: uptime.as_secs(),
+            games_started,
+            games_finished,
+            games_won,
+            games_drawn,
+            games_resigned,
+            games_timed_out,
+            games_started_rate: rate(games_started),
+            games_finished_rate: rate(games_finished),
+        }
+    }
+}
+
+fn saturating_inc(counter: &AtomicU64) {
+    _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_add(1));
+}
@@ -0,0 +1,148 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::board_utils::Board;
+use crate::game_logic::Piece;
+
+// 铁路：双方第 1、5 行（全盘第 1、5、6、10 行）横贯，第 0、4 列从第 1 行
+// 直通到第 10 行；中路第 2 列只在第 5、6 行之间相连
+pub const RAILROAD_ROWS: [u64; 4] = [1, 5, 6, 10];
+pub const RAILROAD_COLUMNS: [u64; 2] = [0, 4];
+
+pub fn is_railroad(x: u64, y: u64) -> bool {
+    x < 5
+        && (RAILROAD_ROWS.contains(&y) || (RAILROAD_COLUMNS.contains(&x) && (1..=10).contains(&y)))
+}
+
+// both squares lie on the same straight stretch of track
+pub fn on_one_line((x, y): (u64, u64), (tx, ty): (u64, u64)) -> bool {
+    if !is_railroad(x, y) || !is_railroad(tx, ty) {
+        return false;
+    }
+    (y == ty && RAILROAD_ROWS.contains(&y))
+        || (x == tx && RAILROAD_COLUMNS.contains(&x))
+        || (x == 2 && tx == 2 && y.abs_diff(ty) == 1)
+}
+
+// A straight run along the track with nothing standing in between. The
+// target itself may be taken, attacking it is up to the caller; flag and
+// landmine never run.
+//
+// This is a client helper. The server never sees the hidden boards, so
+// GameService only checks a move's squares with `legal_step`; a client checks
+// its own move against its board with this before sending it.
+pub fn rail_path_clear(board: &Board, from: (u64, u64), to: (u64, u64)) -> bool {
+    if from == to || !on_one_line(from, to) || !board.get_piece(from.0, from.1).is_movable() {
+        return false;
+    }
+
+    let ((x, y), (tx, ty)) = (from, to);
+    let between: Vec<(u64, u64)> = if y == ty {
+        (x.min(tx) + 1..x.max(tx)).map(|x| (x, y)).collect()
+    } else {
+        (y.min(ty) + 1..y.max(ty)).map(|y| (x, y)).collect()
+    };
+    between
+        .into_iter()
+        .all(|(x, y)| board.get_piece(x, y) == Piece::Empty)
+}
+
+// squares joined to (x, y) by a single length of track
+fn rail_neighbors((x, y): (u64, u64)) -> impl Iterator<Item = (u64, u64)> {
+    [(0, 1), (2, 1), (1, 0), (1, 2)]
+        .into_iter()
+        .filter_map(move |(dx, dy)| Some(((x + dx).checked_sub(1)?, (y + dy).checked_sub(1)?)))
+        .filter(move |&next| on_one_line((x, y), next))
+}
+
+// Every square an engineer on `from` can reach, turning wherever the track
+// does: empty rail squares, and opponent pieces that stop the run. A client
+// helper like `rail_path_clear`.
+pub fn engineer_reachable(board: &Board, from: (u64, u64)) -> HashSet<(u64, u64)> {
+    let mut reachable = HashSet::new();
+    if !is_railroad(from.0, from.1) {
+        return reachable;
+    }
+
+    let mut seen = HashSet::from([from]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(square) = queue.pop_front() {
+        for next in rail_neighbors(square) {
+            if !seen.insert(next) {
+                continue;
+            }
+            match board.get_piece(next.0, next.1) {
+                Piece::Empty => {
+                    reachable.insert(next);
+                    queue.push_back(next);
+                }
+                Piece::Opponent => {
+                    reachable.insert(next);
+                }
+                _ => {}
+            }
+        }
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(pieces: &[((u64, u64), Piece)]) -> Board {
+        let mut board = Board::default();
+        for &((x, y), piece) in pieces {
+            board.place_piece(x, y, piece);
+        }
+        board
+    }
+
+    #[test]
+    fn straight_runs() {
+        let open = board(&[((0, 1), Piece::Captain)]);
+        assert!(rail_path_clear(&open, (0, 1), (0, 10)));
+        assert!(rail_path_clear(&open, (0, 1), (4, 1)));
+        // a turn, a square off the track, or no move at all
+        assert!(!rail_path_clear(&open, (0, 1), (4, 5)));
+        assert!(!rail_path_clear(&open, (0, 1), (1, 2)));
+        assert!(!rail_path_clear(&open, (0, 1), (0, 1)));
+        // column 2 only links the two halves
+        let centre = board(&[((2, 1), Piece::Captain)]);
+        assert!(!rail_path_clear(&centre, (2, 1), (2, 5)));
+
+        let blocked = board(&[((0, 1), Piece::Captain), ((0, 4), Piece::Opponent)]);
+        assert!(rail_path_clear(&blocked, (0, 1), (0, 4)));
+        assert!(!rail_path_clear(&blocked, (0, 1), (0, 5)));
+
+        for piece in [Piece::Flag, Piece::Landmine, Piece::Empty] {
+            let still = board(&[((0, 1), piece)]);
+            assert!(!rail_path_clear(&still, (0, 1), (0, 2)), "{:?}", piece);
+        }
+    }
+
+    #[test]
+    fn engineer_routes_around_the_ring() {
+        // an own piece to the left, the way round is on the right
+        let mut pieces = vec![((2, 1), Piece::Engineer), ((1, 1), Piece::Major)];
+        let reachable = engineer_reachable(&board(&pieces), (2, 1));
+        assert!(reachable.contains(&(2, 5)));
+        assert!(reachable.contains(&(0, 5)));
+        assert!(!reachable.contains(&(1, 1)));
+        assert!(!reachable.contains(&(2, 1)));
+
+        // a captain on the same square only runs straight
+        let captain = board(&[((2, 1), Piece::Captain), ((1, 1), Piece::Major)]);
+        assert!(!rail_path_clear(&captain, (2, 1), (2, 5)));
+        assert!(rail_path_clear(&captain, (2, 1), (4, 1)));
+
+        // an opponent piece on the corner is as far as the run goes
+        pieces.push(((4, 1), Piece::Opponent));
+        let reachable = engineer_reachable(&board(&pieces), (2, 1));
+        assert!(reachable.contains(&(4, 1)));
+        assert!(!reachable.contains(&(4, 2)));
+        assert!(!reachable.contains(&(2, 5)));
+
+        // off the track there is nowhere to go
+        assert!(engineer_reachable(&board(&[((1, 2), Piece::Engineer)]), (1, 2)).is_empty());
+    }
+}
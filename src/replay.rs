@@ -0,0 +1,210 @@
+use std::fs::{read_to_string, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre, Context};
+
+use crate::game_logic::{AttackResult, PieceMove};
+use crate::types::GameSummary;
+
+// Compact move notation, one move per line:
+//   a1-a2            simple move from column a row 1 to column a row 2
+//   b5xb6+           attack, `+` win, `=` draw, `<` lose
+//   ... f=b1 o=c12   revealed flag of the mover / the opponent
+//   ... w=1          game winner
+//   ... n=3 t=1690000000000   move number and timestamp in milliseconds
+// Columns 0-4 are written as a-e, rows 0-11 as 1-12.
+
+pub fn export_text(moves: &[PieceMove]) -> String {
+    moves.iter().map(format_move).collect::<Vec<_>>().join("\n")
+}
+
+pub fn import_text(text: &str) -> eyre::Result<Vec<PieceMove>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(i, line)| parse_move(line).wrap_err_with(|| format!("line {}", i + 1)))
+        .collect()
+}
+
+// A finished game is stored as JSON lines in <dir>/<game_id>.replay.jsonl,
+// its GameSummary first and then one PieceMove per line. The boards are not
+// part of it, the server never sees the hidden pieces.
+
+pub fn replay_path(dir: &Path, game_id: u64) -> PathBuf {
+    dir.join(format!("{}.replay.jsonl", game_id))
+}
+
+pub fn save_game(path: &Path, summary: &GameSummary, moves: &[PieceMove]) -> eyre::Result<()> {
+    let mut file = BufWriter::new(File::create(path).wrap_err("create")?);
+    writeln!(file, "{}", serde_json::to_string(summary)?)?;
+    for piece_move in moves {
+        writeln!(file, "{}", serde_json::to_string(piece_move)?)?;
+    }
+    file.flush().wrap_err("flush")
+}
+
+pub fn load_game(path: &Path) -> eyre::Result<(GameSummary, Vec<PieceMove>)> {
+    let data = read_to_string(path).wrap_err("read")?;
+    let mut lines = data.lines();
+    let summary = lines.next().ok_or_else(|| eyre!("empty replay"))?;
+    let summary = serde_json::from_str(summary).wrap_err("summary")?;
+    let moves = lines
+        .enumerate()
+        .map(|(i, line)| serde_json::from_str(line).wrap_err_with(|| format!("line {}", i + 2)))
+        .collect::<eyre::Result<_>>()?;
+    Ok((summary, moves))
+}
+
+fn format_move(piece_move: &PieceMove) -> String {
+    let from = format_square(piece_move.x, piece_move.y);
+    let to = format_square(piece_move.target_x, piece_move.target_y);
+    let mut notation = match piece_move.attack_result {
+        AttackResult::SimpleMove => format!("{}-{}", from, to),
+        AttackResult::Win => format!("{}x{}+", from, to),
+        AttackResult::Draw => format!("{}x{}=", from, to),
+        AttackResult::Lose => format!("{}x{}<", from, to),
+    };
+
+    if let (Some(x), Some(y)) = (piece_move.flag_x, piece_move.flag_y) {
+        notation.push_str(&format!(" f={}", format_square(x, y)));
+    }
+    if let (Some(x), Some(y)) = (piece_move.opp_flag_x, piece_move.opp_flag_y) {
+        notation.push_str(&format!(" o={}", format_square(x, y)));
+    }
+    if piece_move.game_winner != 0 {
+        notation.push_str(&format!(" w={}", piece_move.game_winner));
+    }
+    if piece_move.move_number != 0 {
+        notation.push_str(&format!(" n={}", piece_move.move_number));
+    }
+    if piece_move.timestamp_ms != 0 {
+        notation.push_str(&format!(" t={}", piece_move.timestamp_ms));
+    }
+    notation
+}
+
+fn parse_move(line: &str) -> eyre::Result<PieceMove> {
+    let mut tokens = line.split_whitespace();
+    let route = tokens.next().ok_or_else(|| eyre!("empty move"))?;
+
+    let (from, rest, attack_result) = if let Some((from, to)) = route.split_once('-') {
+        (from, to, AttackResult::SimpleMove)
+    } else if let Some((from, to)) = route.split_once('x') {
+        let (to, attack_result) = match to.chars().last() {
+            Some('+') => (&to[..to.len() - 1], AttackResult::Win),
+            Some('=') => (&to[..to.len() - 1], AttackResult::Draw),
+            Some('<') => (&to[..to.len() - 1], AttackResult::Lose),
+            _ => bail!("missing attack result in {}", route),
+        };
+        (from, to, attack_result)
+    } else {
+        bail!("invalid route {}", route);
+    };
+    let (x, y) = parse_square(from)?;
+    let (target_x, target_y) = parse_square(rest)?;
+
+    let mut piece_move = PieceMove {
+        x,
+        y,
+        target_x,
+        target_y,
+        attack_result,
+        flag_x: None,
+        flag_y: None,
+        opp_flag_x: None,
+        opp_flag_y: None,
+        game_winner: 0,
+        move_number: 0,
+        timestamp_ms: 0,
+    };
+
+    for token in tokens {
+        match token.split_once('=') {
+            Some(("f", square)) => {
+                let (x, y) = parse_square(square)?;
+                piece_move.flag_x = Some(x);
+                piece_move.flag_y = Some(y);
+            }
+            Some(("o", square)) => {
+                let (x, y) = parse_square(square)?;
+                piece_move.opp_flag_x = Some(x);
+                piece_move.opp_flag_y = Some(y);
+            }
+            Some(("w", winner)) => {
+                piece_move.game_winner = winner.parse().wrap_err("winner")?;
+            }
+            Some(("n", move_number)) => {
+                piece_move.move_number = move_number.parse().wrap_err("move number")?;
+            }
+            Some(("t", timestamp_ms)) => {
+                piece_move.timestamp_ms = timestamp_ms.parse().wrap_err("timestamp")?;
+            }
+            _ => bail!("unknown token {}", token),
+        }
+    }
+
+    Ok(piece_move)
+}
+
+fn format_square(x: u32, y: u32) -> String {
+    format!("{}{}", (b'a' + x as u8) as char, y + 1)
+}
+
+fn parse_square(square: &str) -> eyre::Result<(u32, u32)> {
+    let mut chars = square.chars();
+    let x = match chars.next() {
+        Some(c @ 'a'..='e') => c as u32 - 'a' as u32,
+        _ => bail!("invalid column in {}", square),
+    };
+    let y: u32 = chars.as_str().parse().wrap_err("row")?;
+    if !(1..=12).contains(&y) {
+        bail!("invalid row in {}", square);
+    }
+    Ok((x, y - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAME: &str = "\
+a6-a7 n=1 t=1690000000000
+c7xc6+ n=2 t=1690000001000
+b5xb6= n=3 t=1690000002000
+a7xa8< f=b1 n=4 t=1690000003000
+e8xd12+ o=d12 w=2 n=5 t=1690000004000";
+
+    #[test]
+    fn text_round_trip() {
+        let moves = import_text(GAME).unwrap();
+        assert_eq!(moves.len(), 5);
+        assert_eq!(export_text(&moves), GAME);
+
+        let last = &moves[4];
+        assert_eq!(
+            (last.x, last.y, last.target_x, last.target_y),
+            (4, 7, 3, 11)
+        );
+        assert_eq!(last.attack_result, AttackResult::Win);
+        assert_eq!((last.opp_flag_x, last.opp_flag_y), (Some(3), Some(11)));
+        assert_eq!(last.game_winner, 2);
+        assert_eq!(moves[3].attack_result, AttackResult::Lose);
+        assert_eq!((moves[3].flag_x, moves[3].flag_y), (Some(1), Some(0)));
+
+        // the parsed moves export to json the same as the moves they came from
+        let again = import_text(&export_text(&moves)).unwrap();
+        assert_eq!(
+            serde_json::to_value(&again).unwrap(),
+            serde_json::to_value(&moves).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_notation_is_rejected() {
+        for line in ["a6a7", "a6xa7", "f1-a2", "a0-a1", "a12-a13", "a6-a7 q=1"] {
+            assert!(import_text(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -1,9 +1,13 @@
+use std::{path::PathBuf, time::Duration};
+
 use aleo_rust::{Address, Testnet3};
 use axum::extract::ws::Message;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 
-use crate::game_logic::{MovePos, Piece, PieceMove};
+use crate::board_utils::{SetupViolation, Variant};
+use crate::game_logic::{piece_by_name, AttackResult, MovePos, Piece, PieceMove};
+use crate::short_addr;
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -26,11 +30,17 @@ pub enum GameMessage {
     Ready {
         #[serde_as(as = "DisplayFromStr")]
         game_id: u64,
+        // 随机先手的输入之一，如布阵的哈希或随机数；双方都 Ready 前不公开
+        #[serde(default)]
+        commitment: String,
     },
     GameStart {
         #[serde_as(as = "DisplayFromStr")]
         game_id: u64,
         turn: Address<Testnet3>,
+        // 随机先手时附带抛硬币的输入，供 arbiter 复核
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coin_flip: Option<CoinFlip>,
     },
     Hello {
         #[serde_as(as = "DisplayFromStr")]
@@ -43,6 +53,14 @@ pub enum GameMessage {
         arbiter: Address<Testnet3>,
         player1: Address<Testnet3>,
         player2: Address<Testnet3>,
+        // 双方 join 时填写的显示名
+        #[serde(default)]
+        player1_name: String,
+        #[serde(default)]
+        player2_name: String,
+        // 本局的棋子配置，双方按它布阵
+        #[serde(default)]
+        variant: Variant,
     },
     Move {
         // 行棋方，通知server 行棋路线
@@ -65,6 +83,379 @@ pub enum GameMessage {
         flag_y: Option<u32>,
     },
     MoveResult(PieceMove),
+    PieceRevealed {
+        // 己方棋子阵亡，server 告知该棋子已公开
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        piece: Piece,
+        x: u32,
+        y: u32,
+    },
+    RequestResync {
+        // 客户端发现 seq 不连续时，请求补发 last_seq 之后的公开消息
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        last_seq: u64,
+    },
+    GameOver {
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        // 平局时为空
+        winner: Option<Address<Testnet3>>,
+        reason: GameOverReason,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        summary: Option<GameSummary>,
+    },
+    Error {
+        // server 拒绝客户端消息时告知原因
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        message: String,
+    },
+    Spectators {
+        // 观战人数变化时通知双方及观战者
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        count: u64,
+    },
+    CapturedPieces {
+        // 每次结算后公布该玩家已阵亡的棋子
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        player: Address<Testnet3>,
+        pieces: Vec<Piece>,
+    },
+    Resign {
+        // 认输，任何时候都可以发，对手获胜
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    OfferDraw {
+        // 求和，server 转发给对手，对手 AcceptDraw 后以 agreement 结束
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    AcceptDraw {
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    DeclineDraw {
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    RequestRematch {
+        // 对局结束后请求再来一局，server 转发给对手；双方都请求后在原连接上
+        // 推送新对局的 Role，上局后手的一方先手
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    Requeue {
+        // 对局结束后不再来一局，回到随机匹配队列；配对成功后在原连接上推送
+        // 新对局的 Role，离开队列仍未配对时收到 Error
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+    },
+    Ping {
+        // 应用层心跳，server 原样回 Pong
+        #[serde_as(as = "DisplayFromStr")]
+        nonce: u64,
+    },
+    Pong {
+        #[serde_as(as = "DisplayFromStr")]
+        nonce: u64,
+    },
+    Clock {
+        // 轮到 player 行动时推送，remaining_ms 内未完成 Move/Whisper 判负
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        player: Address<Testnet3>,
+        remaining_ms: u64,
+    },
+    Chat {
+        // 对局内聊天，server 只转发给对手，不影响回合
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        text: String,
+    },
+    Typing {
+        // 正在输入，server 限流后转发给对手和观战者，不计入历史
+        #[serde_as(as = "DisplayFromStr")]
+        game_id: u64,
+        // 客户端不填，由 server 转发时填入
+        #[serde(default)]
+        player: Option<Address<Testnet3>>,
+    },
+    Sequenced {
+        // server 推送给双方和观战者的公开消息，seq 从 1 开始递增
+        seq: u64,
+        message: Box<GameMessage>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameOverReason {
+    FlagCaptured,
+    Resigned,
+    Timeout,
+    Forfeit,
+    Cheat,
+    Agreement,
+    Repetition,
+    NoMoves,
+    // 双方都只剩军棋和地雷，谁也到不了对方军棋
+    Stalemate,
+}
+
+// 对局结束时的汇总，随 GameOver 下发
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GameSummary {
+    #[serde_as(as = "DisplayFromStr")]
+    pub game_id: u64,
+    pub player1: Address<Testnet3>,
+    pub player2: Address<Testnet3>,
+    pub winner: Option<Address<Testnet3>>,
+    pub reason: GameOverReason,
+    // 已结算的着数
+    pub moves: u64,
+    // 从 GameStart 起算，未开局为 0
+    pub duration_secs: u64,
+}
+
+// one-line summary for info logs, the full json is logged at debug. hidden
+// pieces (move/whisper) are left out on purpose
+impl std::fmt::Display for GameMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameMessage::OpponentDisconnected { game_id } => {
+                write!(f, "opponentDisconnected game={}", game_id)
+            }
+            GameMessage::RepeatedLogin { game_id } => write!(f, "repeatedLogin game={}", game_id),
+            GameMessage::Ready { game_id, .. } => write!(f, "ready game={}", game_id),
+            GameMessage::GameStart { game_id, turn, .. } => {
+                write!(f, "gameStart game={} turn={}", game_id, short_addr(turn))
+            }
+            GameMessage::Hello { game_id } => write!(f, "hello game={}", game_id),
+            GameMessage::Role { game_id, .. } => write!(f, "role game={}", game_id),
+            GameMessage::Move {
+                x,
+                y,
+                target_x,
+                target_y,
+                ..
+            } => write!(f, "move ({},{})->({},{})", x, y, target_x, target_y),
+            GameMessage::PiecePos(pos) => write!(
+                f,
+                "piecePos ({},{})->({},{})",
+                pos.x, pos.y, pos.target_x, pos.target_y
+            ),
+            GameMessage::Whisper { x, y, .. } => write!(f, "whisper ({},{})", x, y),
+            GameMessage::MoveResult(mv) => write!(
+                f,
+                "moveResult #{} ({},{})->({},{}) {:?}",
+                mv.move_number, mv.x, mv.y, mv.target_x, mv.target_y, mv.attack_result
+            ),
+            GameMessage::PieceRevealed { game_id, x, y, .. } => {
+                write!(f, "pieceRevealed game={} ({},{})", game_id, x, y)
+            }
+            GameMessage::RequestResync { game_id, last_seq } => {
+                write!(f, "requestResync game={} last_seq={}", game_id, last_seq)
+            }
+            GameMessage::GameOver {
+                game_id,
+                winner,
+                reason,
+                ..
+            } => write!(
+                f,
+                "gameOver game={} winner={} reason={:?}",
+                game_id,
+                winner.as_ref().map(short_addr).unwrap_or("-".into()),
+                reason
+            ),
+            GameMessage::Error { game_id, message } => {
+                write!(f, "error game={} {:?}", game_id, message)
+            }
+            GameMessage::Spectators { game_id, count } => {
+                write!(f, "spectators game={} count={}", game_id, count)
+            }
+            GameMessage::CapturedPieces {
+                game_id,
+                player,
+                pieces,
+            } => write!(
+                f,
+                "capturedPieces game={} player={} count={}",
+                game_id,
+                short_addr(player),
+                pieces.len()
+            ),
+            GameMessage::Resign { game_id } => write!(f, "resign game={}", game_id),
+            GameMessage::OfferDraw { game_id } => write!(f, "offerDraw game={}", game_id),
+            GameMessage::AcceptDraw { game_id } => write!(f, "acceptDraw game={}", game_id),
+            GameMessage::DeclineDraw { game_id } => write!(f, "declineDraw game={}", game_id),
+            GameMessage::RequestRematch { game_id } => {
+                write!(f, "requestRematch game={}", game_id)
+            }
+            GameMessage::Requeue { game_id } => write!(f, "requeue game={}", game_id),
+            GameMessage::Ping { nonce } => write!(f, "ping nonce={}", nonce),
+            GameMessage::Pong { nonce } => write!(f, "pong nonce={}", nonce),
+            GameMessage::Clock {
+                game_id,
+                player,
+                remaining_ms,
+            } => write!(
+                f,
+                "clock game={} player={} remaining_ms={}",
+                game_id,
+                short_addr(player),
+                remaining_ms
+            ),
+            GameMessage::Chat { game_id, text } => {
+                write!(f, "chat game={} chars={}", game_id, text.chars().count())
+            }
+            GameMessage::Typing { game_id, .. } => write!(f, "typing game={}", game_id),
+            GameMessage::Sequenced { seq, message } => write!(f, "#{} {}", seq, message),
+        }
+    }
+}
+
+// constructors for the messages a client sends
+impl GameMessage {
+    pub fn hello(game_id: u64) -> Self {
+        GameMessage::Hello { game_id }
+    }
+
+    /// `commitment` goes into the coin flip for the first move, see `CoinFlip`.
+    ///
+    /// ```
+    /// use land_battle_chess::GameMessage;
+    ///
+    /// let json = serde_json::to_string(&GameMessage::ready(7, "c0ffee")).unwrap();
+    /// assert_eq!(json, r#"{"type":"ready","game_id":"7","commitment":"c0ffee"}"#);
+    /// ```
+    pub fn ready(game_id: u64, commitment: impl Into<String>) -> Self {
+        GameMessage::Ready {
+            game_id,
+            commitment: commitment.into(),
+        }
+    }
+
+    /// `flag` is the flag position, only sent with the field marshal.
+    ///
+    /// ```
+    /// use land_battle_chess::{GameMessage, Piece};
+    ///
+    /// let msg = GameMessage::move_piece(Piece::FieldMarshal, (2, 5), (2, 6), Some((1, 0)));
+    /// assert_eq!(
+    ///     serde_json::to_value(&msg).unwrap(),
+    ///     serde_json::json!({
+    ///         "type": "move",
+    ///         "piece": 12,
+    ///         "x": 2,
+    ///         "y": 5,
+    ///         "target_x": 2,
+    ///         "target_y": 6,
+    ///         "flag_x": 1,
+    ///         "flag_y": 0,
+    ///     })
+    /// );
+    /// ```
+    pub fn move_piece(
+        piece: Piece,
+        (x, y): (u32, u32),
+        (target_x, target_y): (u32, u32),
+        flag: Option<(u32, u32)>,
+    ) -> Self {
+        GameMessage::Move {
+            piece,
+            x,
+            y,
+            target_x,
+            target_y,
+            flag_x: flag.map(|(x, _)| x),
+            flag_y: flag.map(|(_, y)| y),
+        }
+    }
+
+    /// ```
+    /// use land_battle_chess::{GameMessage, Piece};
+    ///
+    /// let msg = GameMessage::whisper(Piece::Landmine, (3, 1), None);
+    /// assert_eq!(
+    ///     serde_json::to_value(&msg).unwrap(),
+    ///     serde_json::json!({
+    ///         "type": "whisper",
+    ///         "piece": 3,
+    ///         "x": 3,
+    ///         "y": 1,
+    ///         "flag_x": null,
+    ///         "flag_y": null,
+    ///     })
+    /// );
+    /// ```
+    pub fn whisper(piece: Piece, (x, y): (u32, u32), flag: Option<(u32, u32)>) -> Self {
+        GameMessage::Whisper {
+            piece,
+            x,
+            y,
+            flag_x: flag.map(|(x, _)| x),
+            flag_y: flag.map(|(_, y)| y),
+        }
+    }
+
+    /// ```
+    /// use land_battle_chess::GameMessage;
+    ///
+    /// let json = serde_json::to_string(&GameMessage::request_resync(7, 12)).unwrap();
+    /// assert_eq!(json, r#"{"type":"requestResync","game_id":"7","last_seq":12}"#);
+    /// ```
+    pub fn request_resync(game_id: u64, last_seq: u64) -> Self {
+        GameMessage::RequestResync { game_id, last_seq }
+    }
+
+    pub fn resign(game_id: u64) -> Self {
+        GameMessage::Resign { game_id }
+    }
+
+    pub fn offer_draw(game_id: u64) -> Self {
+        GameMessage::OfferDraw { game_id }
+    }
+
+    pub fn accept_draw(game_id: u64) -> Self {
+        GameMessage::AcceptDraw { game_id }
+    }
+
+    pub fn decline_draw(game_id: u64) -> Self {
+        GameMessage::DeclineDraw { game_id }
+    }
+
+    pub fn request_rematch(game_id: u64) -> Self {
+        GameMessage::RequestRematch { game_id }
+    }
+
+    pub fn requeue(game_id: u64) -> Self {
+        GameMessage::Requeue { game_id }
+    }
+
+    pub fn ping(nonce: u64) -> Self {
+        GameMessage::Ping { nonce }
+    }
+
+    pub fn chat(game_id: u64, text: impl Into<String>) -> Self {
+        GameMessage::Chat {
+            game_id,
+            text: text.into(),
+        }
+    }
+
+    pub fn typing(game_id: u64) -> Self {
+        GameMessage::Typing {
+            game_id,
+            player: None,
+        }
+    }
 }
 
 impl TryInto<Message> for GameMessage {
@@ -74,24 +465,382 @@ impl TryInto<Message> for GameMessage {
     }
 }
 
+// 先手由 game_id、双方地址和双方 Ready 时提交的 commitment 的 FNV-1a 哈希
+// 决定，双方按地址字典序排列，因此与谁创建房间无关。server 在双方都 Ready
+// 之前不公开 commitment，game_id 又在 Ready 之前已定，server 和任何一方都
+// 无法单独挑选结果；双方可在 GameStart 中核对自己的 commitment 并重算哈希。
+// 哈希最高位为 0 时 players.0 先手（FNV-1a 的最低位只是各字节最低位的异或）。
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CoinFlip {
+    #[serde_as(as = "DisplayFromStr")]
+    pub game_id: u64,
+    pub players: (Address<Testnet3>, Address<Testnet3>),
+    // 与 players 一一对应
+    pub commitments: (String, String),
+    #[serde_as(as = "DisplayFromStr")]
+    pub hash: u64,
+}
+
+impl CoinFlip {
+    // each player with the commitment from their Ready
+    pub fn new(
+        game_id: u64,
+        player1: (Address<Testnet3>, String),
+        player2: (Address<Testnet3>, String),
+    ) -> Self {
+        let (first, second) = if player1.0.to_string() <= player2.0.to_string() {
+            (player1, player2)
+        } else {
+            (player2, player1)
+        };
+
+        let mut bytes = game_id.to_le_bytes().to_vec();
+        for (pubkey, commitment) in [&first, &second] {
+            bytes.extend(pubkey.to_string().into_bytes());
+            // the length keeps one commitment from running into the next
+            bytes.extend((commitment.len() as u64).to_le_bytes());
+            bytes.extend(commitment.as_bytes());
+        }
+
+        CoinFlip {
+            game_id,
+            players: (first.0, second.0),
+            commitments: (first.1, second.1),
+            hash: fnv1a(FNV_OFFSET, &bytes),
+        }
+    }
+
+    pub fn starter(&self) -> Address<Testnet3> {
+        if self.hash >> 63 == 0 {
+            self.players.0
+        } else {
+            self.players.1
+        }
+    }
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// FNV-1a，从 hash 接着往下算，可以分段喂入
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Join {
     pub access_code: String,
     pub pubkey: Address<Testnet3>,
+    // 随机决定先手，由房间创建者设置
+    #[serde(default)]
+    pub random_turn: bool,
+    // 显示名，缺省为缩写的地址
+    pub name: Option<String>,
+    // 棋子配置，同样由房间创建者设置，缺省为标准版
+    #[serde(default)]
+    pub variant: Variant,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JoinError {
+    AccessCodeUsed,
+    GameStarted,
+    // 同时进行的对局数已达上限
+    TooManyGames,
+    // 已在房间或随机匹配队列中等待，不能同时走另一种方式
+    AlreadyWaiting,
+    // 同一个 access code 下的用户数不符合预期
+    Internal,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LobbyCreate {
+    pub pubkey: Address<Testnet3>,
+    #[serde(default)]
+    pub random_turn: bool,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub variant: Variant,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Matchmake {
+    pub pubkey: Address<Testnet3>,
+    pub name: Option<String>,
+    // 只与选了同一配置的玩家配对
+    #[serde(default)]
+    pub variant: Variant,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MatchmakeCancel {
+    pub pubkey: Address<Testnet3>,
+    // /matchmake 返回的 token
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LobbyCreated {
+    // 对手用该 code 调用 /join
+    pub code: String,
+    pub game_id: Option<u64>,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinGetQuery {
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EnterGameError {
+    NotFound,
+    NotParticipant,
+    // 对局已结束并被清理
+    Finished,
+    // join 返回的票据缺失、签名不对或与 game_id/player 不符
+    InvalidTicket,
+    TicketExpired,
+    // 缺少玩家私钥对 ticket 的签名，或签名与 player 不符
+    InvalidSignature,
+    // ticket 已用过一次，重连需从 /join/:pubkey 重新获取
+    TicketUsed,
 }
 
 #[serde_as]
 #[derive(Serialize)]
 pub enum AppResponse {
     Error(String),
+    JoinError(JoinError),
+    EnterGameError(EnterGameError),
     JoinResult {
+        // 0 表示仍在等待对手，真实的 game_id 不会是 0
         #[serde_as(as = "DisplayFromStr")]
         game_id: u64,
+        // 进入对局的凭证，arbiter 对 game_id、pubkey 和过期时间的签名
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ticket: Option<String>,
+        // 首次加入时发放，之后凭它调用 /join/:pubkey 换取 ticket
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
     },
+    Replay(Vec<PieceMove>),
+    // 已退出随机匹配队列
+    Cancelled,
+    // 进入对局时未带 ticket 或签名
+    Unauthorized,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EnterGame {
     pub player: Address<Testnet3>,
     pub game_id: u64,
+    // ticket 及 player 私钥对其原文的签名放在 x-ticket / x-ticket-signature
+    // 请求头中，不进查询串，以免被请求日志记下
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Spectate {
+    pub game_id: u64,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayFormat {
+    #[default]
+    Json,
+    // 见 replay::export_text
+    Text,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    #[serde(default)]
+    pub format: ReplayFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateBoardQuery {
+    #[serde(default)]
+    pub variant: Variant,
+}
+
+// 审计日志中的一条消息，from/to 为空表示 server
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub from: Option<Address<Testnet3>>,
+    pub to: Option<Address<Testnet3>>,
+    pub message: GameMessage,
+}
+
+// 对局结束时 arbiter 对审计记录的签名。hash 从 FNV_OFFSET 起依次喂入前
+// entries 条记录的 json 行（含换行），与审计文件逐行对应；环形缓冲淘汰的
+// 记录也计算在内
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TranscriptSignature {
+    #[serde_as(as = "DisplayFromStr")]
+    pub game_id: u64,
+    pub entries: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub hash: u64,
+    pub arbiter: Address<Testnet3>,
+    pub signature: String,
+}
+
+impl TranscriptSignature {
+    // what the arbiter signs, "<game_id>:<entries>:<hash>"
+    pub fn message(game_id: u64, entries: u64, hash: u64) -> String {
+        format!("{}:{}:{}", game_id, entries, hash)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TurnResult {
+    pub turn: Address<Testnet3>,
+    // 已结算的着数
+    pub move_number: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Adjudicate {
+    pub attacker: Piece,
+    pub defender: Piece,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdjudicateResult {
+    pub attack_result: AttackResult,
+}
+
+// 一格对战规则，/rules/combat 返回全部可能的 attacker x defender 组合
+#[derive(Debug, Serialize)]
+pub struct CombatRule {
+    #[serde(with = "piece_by_name")]
+    pub attacker: Piece,
+    #[serde(with = "piece_by_name")]
+    pub defender: Piece,
+    pub attack_result: AttackResult,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidateBoardResult {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<SetupViolation>,
+}
+
+// 单局内存上限，超出 history_cap 的公开消息在配置了 spill_dir 时写入磁盘
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub history_cap: usize,
+    pub audit_cap: usize,
+    pub spill_dir: Option<PathBuf>,
+    // 对局结束时保存回放的目录，未配置则不保存
+    pub replay_dir: Option<PathBuf>,
+    // 审计记录逐条追加到该目录，对局结束时写入 arbiter 签名，未配置则不保存
+    pub audit_dir: Option<PathBuf>,
+}
+
+// 各类超时配置，由启动参数指定
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub turn: Duration,
+    pub whisper: Duration,
+    pub lobby_ttl: Duration,
+    pub heartbeat: Duration,
+    // 单次 ws 发送的上限，超时计为一次发送失败
+    pub send: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn addr() -> Address<Testnet3> {
+        Address::from_str("aleo17e9qgem7pvh44yw6takrrtvnf9m6urpmlwf04ytghds7d2dfdcpqtcy8cj")
+            .unwrap()
+    }
+
+    #[test]
+    fn display_summary() {
+        assert_eq!(GameMessage::ready(7, "c0ffee").to_string(), "ready game=7");
+        assert_eq!(
+            GameMessage::move_piece(Piece::General, (2, 5), (2, 6), None).to_string(),
+            "move (2,5)->(2,6)"
+        );
+        // the hidden piece of a whisper is not logged
+        assert_eq!(
+            GameMessage::whisper(Piece::Flag, (1, 0), None).to_string(),
+            "whisper (1,0)"
+        );
+        assert_eq!(
+            GameMessage::GameOver {
+                game_id: 7,
+                winner: Some(addr()),
+                reason: GameOverReason::FlagCaptured,
+                summary: None,
+            }
+            .to_string(),
+            "gameOver game=7 winner=aleo17e9…y8cj reason=FlagCaptured"
+        );
+        assert_eq!(
+            GameMessage::Sequenced {
+                seq: 3,
+                message: Box::new(GameMessage::chat(7, "你好")),
+            }
+            .to_string(),
+            "#3 chat game=7 chars=2"
+        );
+    }
+
+    fn other_addr() -> Address<Testnet3> {
+        Address::from_str("aleo12m0ks7kd78ulf4669v2maynerc3jhj2ukkxyw6mdv6rag6xw8cpqdpm4vm")
+            .unwrap()
+    }
+
+    #[test]
+    fn coin_flip_is_reproducible_and_symmetric() {
+        let (a, b) = (addr(), other_addr());
+        let flip = CoinFlip::new(7, (a, "alpha".into()), (b, "beta".into()));
+        assert_eq!(
+            CoinFlip::new(7, (a, "alpha".into()), (b, "beta".into())),
+            flip
+        );
+        // whoever created the lobby, the same inputs give the same starter
+        let swapped = CoinFlip::new(7, (b, "beta".into()), (a, "alpha".into()));
+        assert_eq!(swapped, flip);
+        assert_eq!(swapped.starter(), flip.starter());
+        assert_eq!(flip.players, (b, a));
+        assert_eq!(flip.commitments, ("beta".to_string(), "alpha".to_string()));
+
+        // either player's commitment alone can turn the result
+        let starters: Vec<_> = (0..16)
+            .map(|i| CoinFlip::new(7, (a, "alpha".into()), (b, i.to_string())).starter())
+            .collect();
+        assert!(starters.contains(&a) && starters.contains(&b));
+
+        // and commitments are not run together
+        assert_ne!(
+            CoinFlip::new(7, (a, "ab".into()), (b, "c".into())).hash,
+            CoinFlip::new(7, (a, "a".into()), (b, "bc".into())).hash
+        );
+
+        let json = serde_json::to_string(&flip).unwrap();
+        assert_eq!(serde_json::from_str::<CoinFlip>(&json).unwrap(), flip);
+    }
 }